@@ -1,80 +1,205 @@
-#![allow(unused)]
+use core::fmt::Write;
 
-use crate::fake_std::{String, ToString};
-use crate::{
-    one_way_mmap_heap::OneWayMmapHeap,
-    utils::{fcntl, ftruncate, get_pid, make_dir_if_not_exists, mkdir, mkstemp, unlink},
-};
-use core::mem::size_of_val;
-use core::ptr::null_mut;
+use arrayvec::ArrayString;
 use libc::c_char;
 
-const TMP_DIR: &str = "/tmp/";
+use crate::utils::{
+    close, ftruncate, get_pid, getenv, memfd_create, mkdir, mkstemp, rmdir, unlink, Result,
+};
 
-pub fn open_shm_span_file(size: usize) -> i32 {
-    let span_dir = {
-        let mut span_dir = open_span_dir().unwrap();
-        // this is required for mkstemp
-        span_dir.push_parts(&["XXXXXX\0"])
-    };
-    let path = span_dir.as_ptr() as *const i8 as *mut i8;
-    unsafe {
-        let fd = mkstemp(path).unwrap();
-        // unlink(path).unwrap();
-        ftruncate(fd, size).unwrap();
-        let _ = fcntl(fd);
+const TMP_DIR: &str = "/tmp";
+pub(crate) const HUGETLBFS_DIR: &str = "/dev/hugepages";
+const SPAN_FILE_NAME: &[u8] = b"messloc-spans\0";
+// attempts at finding an unused span directory name
+const MAX_SPAN_DIRS: usize = 1024;
+
+/// What backs the arena's spans, picked at startup through `MESSLOC_BACKING`
+/// (`memfd`, `tmpfs`, `hugetlbfs` or `anonymous`). File backings are created
+/// in `MESSLOC_SPAN_DIR` if it is set. A backing that can't be set up falls
+/// back to the next one, down to anonymous memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backing {
+    /// An anonymous memfd
+    Memfd,
+    /// An unlinked file in a temporary directory, `/tmp` by default
+    Tmpfs,
+    /// An unlinked file on a hugetlbfs mount, `/dev/hugepages` by default.
+    /// The spans are mapped with `MAP_NORESERVE`, so touching one while the
+    /// huge page pool is empty raises `SIGBUS` instead of failing the
+    /// allocation.
+    HugeTlbFs,
+    /// Private anonymous memory without a file
+    Anonymous,
+}
+
+impl Backing {
+    /// The backing configured in the environment, read without allocating
+    pub fn from_env() -> Self {
+        getenv(b"MESSLOC_BACKING\0")
+            .and_then(Self::parse)
+            .unwrap_or(Self::Memfd)
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "memfd" => Some(Self::Memfd),
+            "tmpfs" => Some(Self::Tmpfs),
+            "hugetlbfs" => Some(Self::HugeTlbFs),
+            "anonymous" => Some(Self::Anonymous),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Memfd => "memfd",
+            Self::Tmpfs => "tmpfs",
+            Self::HugeTlbFs => "hugetlbfs",
+            Self::Anonymous => "anonymous",
+        }
+    }
+
+    /// The backing to try when this one can't be set up
+    pub const fn fallback(self) -> Option<Self> {
+        match self {
+            Self::HugeTlbFs => Some(Self::Memfd),
+            Self::Memfd => Some(Self::Tmpfs),
+            Self::Tmpfs => Some(Self::Anonymous),
+            Self::Anonymous => None,
+        }
+    }
+
+    /// Meshing remaps single pages of the span file, which needs a file with
+    /// base sized pages
+    pub const fn can_mesh(self) -> bool {
+        matches!(self, Self::Memfd | Self::Tmpfs)
+    }
+}
 
-        fd
+/// Open the file backing the arena's spans, `size` bytes long, or `None` for
+/// anonymous memory. Files are unlinked right away, so nothing is left on disk.
+pub fn open_span_file(backing: Backing, size: usize) -> Result<Option<i32>> {
+    let fd = match backing {
+        Backing::Memfd => unsafe {
+            memfd_create(SPAN_FILE_NAME.as_ptr().cast(), libc::MFD_CLOEXEC)
+        },
+        Backing::Tmpfs => open_tmp_span_file(span_dir(TMP_DIR)),
+        Backing::HugeTlbFs => open_tmp_span_file(span_dir(HUGETLBFS_DIR)),
+        Backing::Anonymous => return Ok(None),
+    }?;
+    if let Err(err) = unsafe { ftruncate(fd, size) } {
+        let _ = unsafe { close(fd) };
+        return Err(err);
     }
+    Ok(Some(fd))
 }
 
-fn open_span_dir() -> Option<String> {
+/// Tell the user on stderr that the spans couldn't be backed by `backing`.
+/// The message is formatted on the stack and only carries the error number,
+/// as describing the error would allocate.
+pub fn report_fallback(backing: Backing, fallback: Backing, err: &std::io::Error) {
+    let mut message = ArrayString::<128>::new();
+    let _ = writeln!(
+        message,
+        "messloc: can't back spans by {} (os error {}), falling back to {}",
+        backing.name(),
+        err.raw_os_error().unwrap_or(0),
+        fallback.name()
+    );
+    unsafe { libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len()) };
+}
+
+fn span_dir(default: &'static str) -> &'static str {
+    getenv(b"MESSLOC_SPAN_DIR\0")
+        .filter(|dir| !dir.is_empty())
+        .unwrap_or(default)
+}
+
+/// Create a file in a fresh `<dir>/alloc-mesh-<pid>.<n>` directory and remove
+/// both again right away, leaving the descriptor as the only reference
+fn open_tmp_span_file(dir: &str) -> Result<i32> {
+    let too_long = || std::io::Error::from_raw_os_error(libc::ENAMETOOLONG);
+    let dir = dir.trim_end_matches('/');
+
+    // formatted on the stack, the allocator must not allocate from itself here
+    let mut path = ArrayString::<512>::new();
     let pid = get_pid();
     let mut count = 1;
-    unsafe {
-        let size = core::mem::size_of_val("/tmp/alloc_mesh-XXXXXXXXX.YYYY");
-        let mut path =
-            core::ptr::slice_from_raw_parts_mut(OneWayMmapHeap.malloc(size), size) as *mut [u8; 30];
-        path.write(*b"/tmp/alloc-mesh-XXXXXXXXX.YYYY");
-        let path = path.as_mut().unwrap();
-        let dir = &path[..10];
-        make_dir_if_not_exists(dir.as_ptr() as *mut i8).unwrap();
-        let pid = ToString::to_string(&pid);
-        let pid = pid.as_bytes();
-        path.iter_mut()
-            .skip_while(|x| **x != b'X')
-            .enumerate()
-            .take(10)
-            .for_each(|(k, x)| {
-                if pid[k] > b'\0' {
-                    *x = pid[k];
-                } else {
-                    *x = b'0';
-                }
-            });
-
-        loop {
-            let i = ToString::to_string(&count);
-            let i = i.as_bytes();
-            path.iter_mut()
-                .skip_while(|x| **x != b'Y')
-                .enumerate()
-                .take(4)
-                .for_each(|(k, x)| {
-                    if i[k] > b'\0' {
-                        *x = pid[k];
-                    } else {
-                        *x = b'0';
-                    }
-                });
-            let p = path.as_ptr() as *mut i8;
-            if mkdir(p).is_ok() {
-                return Some(String::new(p.cast(), path.len()));
-            } else if count >= 1024 {
-                break;
+    loop {
+        path.clear();
+        write!(path, "{dir}/alloc-mesh-{pid}.{count}\0").map_err(|_| too_long())?;
+        match unsafe { mkdir(path.as_ptr().cast_mut().cast()) } {
+            Ok(()) => break,
+            Err(err)
+                if err.kind() == std::io::ErrorKind::AlreadyExists && count < MAX_SPAN_DIRS =>
+            {
+                count += 1;
             }
-            count += 1;
+            Err(err) => return Err(err),
+        }
+    }
+
+    let dir_len = path.len();
+    path.truncate(dir_len - 1);
+    let fd = match path.try_push_str("/XXXXXX\0") {
+        Ok(()) => unsafe { mkstemp(path.as_ptr().cast_mut().cast()) },
+        Err(_) => Err(too_long()),
+    };
+    unsafe {
+        if fd.is_ok() {
+            let _ = unlink(path.as_ptr().cast_mut().cast::<c_char>());
         }
+        path.truncate(dir_len - 1);
+        path.push('\0');
+        let _ = rmdir(path.as_ptr().cast_mut().cast());
+    }
+    fd
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tmp_span_files_leave_nothing_behind() {
+        let fd = open_tmp_span_file("/tmp/").unwrap();
+        unsafe { ftruncate(fd, 1 << 20) }.unwrap();
+
+        let mut link = ArrayString::<64>::new();
+        let _ = write!(link, "/proc/self/fd/{fd}\0");
+        let mut target = [0u8; 256];
+        let len = unsafe {
+            libc::readlink(
+                link.as_ptr().cast(),
+                target.as_mut_ptr().cast(),
+                target.len(),
+            )
+        };
+        let target = core::str::from_utf8(&target[..usize::try_from(len).unwrap()]).unwrap();
+        assert!(target.starts_with("/tmp/alloc-mesh-"));
+        assert!(target.ends_with(" (deleted)"));
+
+        let dir = &target[..target.rfind('/').unwrap()];
+        assert!(!std::path::Path::new(dir).exists());
+        unsafe { libc::close(fd) };
+
+        assert!(open_tmp_span_file("/nonexistent").is_err());
+    }
+
+    #[test]
+    fn backings_are_parsed_by_name() {
+        assert_eq!(Backing::parse("tmpfs"), Some(Backing::Tmpfs));
+        assert_eq!(Backing::parse("hugetlbfs"), Some(Backing::HugeTlbFs));
+        assert_eq!(Backing::parse("anonymous"), Some(Backing::Anonymous));
+        assert_eq!(Backing::parse("Memfd"), None);
+        assert!(Backing::Memfd.can_mesh() && !Backing::Anonymous.can_mesh());
+        let chain = core::iter::successors(Some(Backing::HugeTlbFs), |b| b.fallback());
+        assert!(chain
+            .map(Backing::name)
+            .eq(["hugetlbfs", "memfd", "tmpfs", "anonymous"]));
+        assert!(["memfd", "tmpfs", "hugetlbfs", "anonymous"]
+            .into_iter()
+            .all(|name| Backing::parse(name).map(Backing::name) == Some(name)));
+        assert_eq!(open_span_file(Backing::Anonymous, 1 << 20).unwrap(), None);
     }
-    None
 }
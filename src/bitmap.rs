@@ -0,0 +1,87 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{comparatomic::Comparatomic, MAX_OBJECTS_PER_MINI_HEAP};
+
+pub const BITMAP_WORDS: usize = MAX_OBJECTS_PER_MINI_HEAP / 64;
+
+/// Occupancy bitmap of a span, one bit per object slot
+pub struct Bitmap {
+    bits: [Comparatomic<AtomicU64>; BITMAP_WORDS],
+}
+
+impl Bitmap {
+    pub fn new() -> Self {
+        Self {
+            bits: core::array::from_fn(|_| Comparatomic::new(0)),
+        }
+    }
+
+    pub const fn bits(&self) -> &[Comparatomic<AtomicU64>; BITMAP_WORDS] {
+        &self.bits
+    }
+
+    const fn locate(index: usize) -> (usize, u64) {
+        (index / 64, 1 << (index % 64))
+    }
+
+    /// Set the bit at `index`, returning `true` if it was previously unset
+    pub fn try_set(&self, index: usize) -> bool {
+        let (word, mask) = Self::locate(index);
+        self.bits[word].inner().fetch_or(mask, Ordering::AcqRel) & mask == 0
+    }
+
+    /// Clear the bit at `index`, returning `true` if it was previously set
+    pub fn unset(&self, index: usize) -> bool {
+        let (word, mask) = Self::locate(index);
+        self.bits[word].inner().fetch_and(!mask, Ordering::AcqRel) & mask != 0
+    }
+
+    pub fn is_set(&self, index: usize) -> bool {
+        let (word, mask) = Self::locate(index);
+        self.bits[word].load(Ordering::Acquire) & mask != 0
+    }
+
+    pub fn in_use_count(&self) -> usize {
+        self.bits
+            .iter()
+            .map(|word| word.load(Ordering::Acquire).count_ones() as usize)
+            .sum()
+    }
+
+    /// Iterate over the unset bits below `limit`
+    pub fn free_indices(&self, limit: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..limit).filter(|index| !self.is_set(*index))
+    }
+
+    /// Set every bit below `limit`
+    pub fn fill(&self, limit: usize) {
+        for index in 0..limit {
+            self.try_set(index);
+        }
+    }
+
+    pub fn clear(&self) {
+        self.bits
+            .iter()
+            .for_each(|word| word.store(0, Ordering::Release));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_and_unset_track_occupancy() {
+        let bitmap = Bitmap::new();
+        assert!(bitmap.try_set(3));
+        assert!(!bitmap.try_set(3));
+        assert!(bitmap.try_set(64));
+        assert_eq!(bitmap.in_use_count(), 2);
+        assert!(bitmap.unset(3));
+        assert!(!bitmap.unset(3));
+        assert!(!bitmap.is_set(3));
+        assert!(bitmap.is_set(64));
+        assert_eq!(bitmap.free_indices(66).count(), 65);
+    }
+}
@@ -0,0 +1,269 @@
+//! C allocation functions backed by a process wide [`Messloc`], so that
+//! unmodified programs can use it through `LD_PRELOAD=libmessloc.so`.
+
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    ffi::{c_int, c_void},
+    ptr::null_mut,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use libc::size_t;
+use spin::Once;
+
+use crate::{Messloc, PAGE_SIZE};
+
+/// Alignment of `max_align_t`, which every `malloc` result has to satisfy
+const MIN_ALIGN: usize = 16;
+const BOOTSTRAP_SIZE: usize = 64 << 10;
+const BOOTSTRAP_WORDS: usize = BOOTSTRAP_SIZE / WORD;
+// every bootstrap allocation is preceded by its size
+const BOOTSTRAP_HEADER: usize = MIN_ALIGN;
+const WORD: usize = core::mem::size_of::<usize>();
+
+static INSTANCE: Once<Messloc> = Once::new();
+static INIT_THREAD: AtomicU64 = AtomicU64::new(0);
+
+/// Memory handed out while the allocator is being initialized, in case that
+/// ends up calling back into `malloc`. It is never reclaimed.
+#[repr(C, align(16))]
+struct Bootstrap(UnsafeCell<[usize; BOOTSTRAP_WORDS]>);
+
+unsafe impl Sync for Bootstrap {}
+
+static BOOTSTRAP: Bootstrap = Bootstrap(UnsafeCell::new([0; BOOTSTRAP_WORDS]));
+static BOOTSTRAP_USED: AtomicUsize = AtomicUsize::new(0);
+
+/// The process wide allocator, or `None` if the calling thread is the one
+/// initializing it. Other threads wait for the initialization to finish.
+fn messloc() -> Option<&'static Messloc> {
+    if let Some(messloc) = INSTANCE.get() {
+        return Some(messloc);
+    }
+
+    let this_thread = unsafe { libc::pthread_self() };
+    if INIT_THREAD.load(Ordering::Acquire) == this_thread {
+        return None;
+    }
+    let mut initialized = false;
+    let messloc = INSTANCE.call_once(|| {
+        INIT_THREAD.store(this_thread, Ordering::Release);
+        initialized = true;
+        Messloc::init()
+    });
+    // registering may allocate, which is fine once the instance is set up
+    if initialized {
+        messloc.install_fork_handlers();
+    }
+    Some(messloc)
+}
+
+fn bootstrap_alloc(size: usize) -> *mut c_void {
+    let Some(needed) = size
+        .checked_add(BOOTSTRAP_HEADER + MIN_ALIGN - 1)
+        .map(|size| size & !(MIN_ALIGN - 1))
+    else {
+        return null_mut();
+    };
+    let start = BOOTSTRAP_USED.fetch_add(needed, Ordering::Relaxed);
+    if start + needed > BOOTSTRAP_SIZE {
+        return null_mut();
+    }
+
+    unsafe {
+        let header = BOOTSTRAP.0.get().cast::<usize>().add(start / WORD);
+        header.write(size);
+        header.add(BOOTSTRAP_HEADER / WORD).cast()
+    }
+}
+
+fn is_bootstrap(ptr: *mut c_void) -> bool {
+    let begin = BOOTSTRAP.0.get() as usize;
+    (begin..begin + BOOTSTRAP_SIZE).contains(&(ptr as usize))
+}
+
+unsafe fn bootstrap_size(ptr: *mut c_void) -> usize {
+    ptr.cast::<usize>().sub(BOOTSTRAP_HEADER / WORD).read()
+}
+
+fn set_errno(errno: c_int) {
+    unsafe { *libc::__errno_location() = errno };
+}
+
+/// Allocate `size` bytes aligned to `align`, setting `errno` on failure
+unsafe fn allocate(align: usize, size: usize, zeroed: bool) -> *mut c_void {
+    let ptr = match (messloc(), Layout::from_size_align(size.max(1), align)) {
+        (Some(messloc), Ok(layout)) if zeroed => messloc.allocate_zeroed(layout).cast(),
+        (Some(messloc), Ok(layout)) => messloc.allocate(layout).cast(),
+        // the static bootstrap memory is already zero
+        (None, Ok(layout)) if layout.align() <= MIN_ALIGN => bootstrap_alloc(layout.size()),
+        _ => null_mut(),
+    };
+    if ptr.is_null() {
+        set_errno(libc::ENOMEM);
+    }
+    ptr
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc(size: size_t) -> *mut c_void {
+    allocate(MIN_ALIGN, size, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn free(ptr: *mut c_void) {
+    if ptr.is_null() || is_bootstrap(ptr) {
+        return;
+    }
+    if let Some(messloc) = messloc() {
+        messloc.free(ptr.cast());
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn calloc(count: size_t, size: size_t) -> *mut c_void {
+    let Some(bytes) = count.checked_mul(size) else {
+        set_errno(libc::ENOMEM);
+        return null_mut();
+    };
+    allocate(MIN_ALIGN, bytes, true)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    if ptr.is_null() {
+        return malloc(size);
+    }
+    if size == 0 {
+        free(ptr);
+        return null_mut();
+    }
+
+    if is_bootstrap(ptr) {
+        let new_ptr = malloc(size);
+        if !new_ptr.is_null() {
+            let old_size = bootstrap_size(ptr);
+            core::ptr::copy_nonoverlapping(ptr.cast::<u8>(), new_ptr.cast(), old_size.min(size));
+        }
+        return new_ptr;
+    }
+
+    let resized = messloc().and_then(|messloc| {
+        let old_size = messloc.usable_size(ptr.cast())?;
+        let layout = Layout::from_size_align(old_size, MIN_ALIGN).ok()?;
+        Some(
+            messloc
+                .reallocate(ptr.cast(), layout, size)
+                .cast::<c_void>(),
+        )
+    });
+    match resized {
+        Some(new_ptr) if !new_ptr.is_null() => new_ptr,
+        _ => {
+            set_errno(libc::ENOMEM);
+            null_mut()
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn posix_memalign(
+    memptr: *mut *mut c_void,
+    align: size_t,
+    size: size_t,
+) -> c_int {
+    if !align.is_power_of_two() || align % core::mem::size_of::<*mut c_void>() != 0 {
+        return libc::EINVAL;
+    }
+
+    let ptr = allocate(align.max(MIN_ALIGN), size, false);
+    if ptr.is_null() {
+        return libc::ENOMEM;
+    }
+    *memptr = ptr;
+    0
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn aligned_alloc(align: size_t, size: size_t) -> *mut c_void {
+    if !align.is_power_of_two() {
+        set_errno(libc::EINVAL);
+        return null_mut();
+    }
+    allocate(align.max(MIN_ALIGN), size, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn memalign(align: size_t, size: size_t) -> *mut c_void {
+    aligned_alloc(align, size)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn valloc(size: size_t) -> *mut c_void {
+    allocate(PAGE_SIZE, size, false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn pvalloc(size: size_t) -> *mut c_void {
+    let Some(size) = size.checked_add(PAGE_SIZE - 1) else {
+        set_errno(libc::ENOMEM);
+        return null_mut();
+    };
+    allocate(PAGE_SIZE, size & !(PAGE_SIZE - 1), false)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn malloc_usable_size(ptr: *mut c_void) -> size_t {
+    if ptr.is_null() {
+        0
+    } else if is_bootstrap(ptr) {
+        bootstrap_size(ptr)
+    } else {
+        messloc()
+            .and_then(|messloc| messloc.usable_size(ptr.cast()))
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn c_allocation_functions_round_trip() {
+        unsafe {
+            let ptr = malloc(10).cast::<u8>();
+            assert_eq!(ptr as usize % MIN_ALIGN, 0);
+            assert_eq!(malloc_usable_size(ptr.cast()), 16);
+            ptr.write_bytes(5, 10);
+
+            let grown = realloc(ptr.cast(), 50_000).cast::<u8>();
+            assert!((0..10).all(|k| *grown.add(k) == 5));
+            assert!(malloc_usable_size(grown.cast()) >= 50_000);
+            free(grown.cast());
+
+            let zeroed = calloc(100, 8).cast::<u64>();
+            assert!((0..100).all(|k| *zeroed.add(k) == 0));
+            free(zeroed.cast());
+            assert!(calloc(usize::MAX, 2).is_null());
+
+            let mut aligned = null_mut();
+            assert_eq!(posix_memalign(&mut aligned, 256, 24), 0);
+            assert_eq!(aligned as usize % 256, 0);
+            assert_eq!(posix_memalign(&mut aligned, 24, 24), libc::EINVAL);
+            assert_eq!(valloc(1) as usize % PAGE_SIZE, 0);
+            assert_eq!(malloc_usable_size(pvalloc(1)), PAGE_SIZE);
+        }
+    }
+
+    #[test]
+    fn bootstrap_memory_is_served_while_initializing() {
+        let ptr = bootstrap_alloc(24);
+        assert!(is_bootstrap(ptr));
+        assert_eq!(unsafe { bootstrap_size(ptr) }, 24);
+        assert_eq!(ptr as usize % MIN_ALIGN, 0);
+        // frees of bootstrap memory are ignored
+        unsafe { free(ptr) };
+    }
+}
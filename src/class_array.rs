@@ -1,3 +1,11 @@
+use crate::NUM_BINS;
+
+/// Object size of each size class, indexed by the values stored in `CLASS_ARRAY`
+pub static CLASS_TO_SIZE: [u32; NUM_BINS] = [
+    0, 16, 32, 48, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 640, 768, 896,
+    1024, 2048, 4096, 8192, 16384,
+];
+
 pub static CLASS_ARRAY: [u32; 249] = [
     // small size classes
     1,  //     0 ->    16
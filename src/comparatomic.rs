@@ -1,6 +1,6 @@
 use core::{
     ops::BitAnd,
-    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, Ordering},
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering},
 };
 
 #[derive(Default)]
@@ -127,6 +127,34 @@ impl Atomic for AtomicU32 {
         self.fetch_add(value, ordering);
     }
 }
+impl Atomic for AtomicUsize {
+    type Innermost = usize;
+    fn make(input: Self::Innermost) -> Self {
+        Self::new(input)
+    }
+
+    fn cas(
+        &self,
+        current: Self::Innermost,
+        new: Self::Innermost,
+        success: Ordering,
+        failure: Ordering,
+    ) -> Result<usize, usize> {
+        self.compare_exchange(current, new, success, failure)
+    }
+
+    fn load_value(&self, ordering: Ordering) -> usize {
+        self.load(ordering)
+    }
+
+    fn store_value(&self, value: Self::Innermost, ordering: Ordering) {
+        self.store(value, ordering);
+    }
+
+    fn fetch_add(&self, value: Self::Innermost, ordering: Ordering) {
+        self.fetch_add(value, ordering);
+    }
+}
 impl<T> Atomic for AtomicPtr<T> {
     type Innermost = *mut T;
     fn make(input: Self::Innermost) -> Self {
@@ -1,21 +1,40 @@
-use crate::one_way_mmap_heap::OneWayMmapHeap;
+use crate::{
+    one_way_mmap_heap::{Mapping, OneWayMmapHeap},
+    utils::munmap,
+    PAGE_SIZE,
+};
 
 pub struct DynArray<T, const N: usize> {
     pointers: *mut Option<*mut T>,
+    /// backs the elements handed over through `write_at`, mapped on first use
+    elements: Option<Mapping>,
 }
 
 impl<T, const N: usize> DynArray<T, N> {
     pub fn create() -> Self {
         let size = core::mem::size_of::<Option<*mut T>>() * N;
         let pointers = unsafe { OneWayMmapHeap.malloc(size) } as *mut Option<*mut T>;
-        let pointer_slice =
-            core::ptr::slice_from_raw_parts_mut(pointers, N) as *mut [Option<*mut T>; N];
-        unsafe { pointer_slice.write([None; N]) };
+        // written slot by slot so that large registries don't need an `N` sized
+        // temporary on the stack
+        (0..N).for_each(|i| unsafe { pointers.add(i).write(None) });
         Self {
             pointers: pointers.cast(),
+            elements: None,
         }
     }
 
+    /// Size of the mapping behind `pointers`
+    const fn pointers_size() -> usize {
+        (core::mem::size_of::<Option<*mut T>>() * N + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+    }
+
+    /// The storage for the element at `at` if it was written by `write_at`
+    fn owned_element(&self, at: usize) -> Option<*mut T> {
+        let ele = unsafe { self.elements.as_ref()?.as_ptr::<T>().add(at) };
+        let slice = unsafe { self.as_slice().as_ref().unwrap() };
+        (slice[at] == Some(ele)).then_some(ele)
+    }
+
     pub fn as_slice(&self) -> *const [Option<*mut T>] {
         core::ptr::slice_from_raw_parts(self.pointers.cast::<Option<*mut T>>(), N)
     }
@@ -42,8 +61,13 @@ impl<T, const N: usize> DynArray<T, N> {
 
     pub fn write_at(&mut self, at: usize, element: T) {
         if at < N {
-            let size = core::mem::size_of::<T>();
-            let ele = unsafe { OneWayMmapHeap.malloc(size) } as *mut T;
+            if let Some(old) = self.owned_element(at) {
+                unsafe { old.drop_in_place() };
+            }
+            let elements = self
+                .elements
+                .get_or_insert_with(|| Mapping::new(core::mem::size_of::<T>() * N));
+            let ele = unsafe { elements.as_ptr::<T>().add(at) };
             unsafe { ele.write(element) };
             let slice = unsafe { self.as_mut_slice().as_mut().unwrap() };
             slice[at] = Some(ele);
@@ -57,12 +81,31 @@ impl<T, const N: usize> DynArray<T, N> {
     }
 }
 
+/// Drops the elements written by `write_at`. Pointers placed into the slots
+/// directly are not owned by the array and left alone.
+impl<T, const N: usize> Drop for DynArray<T, N> {
+    fn drop(&mut self) {
+        if self.pointers.is_null() {
+            return;
+        }
+        for at in 0..N {
+            if let Some(ele) = self.owned_element(at) {
+                unsafe { ele.drop_in_place() };
+            }
+        }
+        let _ = unsafe { munmap(self.pointers.cast(), Self::pointers_size()) };
+    }
+}
+
+// no longer used by the shuffle vectors, kept around for fixed size queues
+#[allow(dead_code)]
 pub struct DynDeq<T, const N: usize> {
     pointers: *mut Option<*mut T>,
     front: usize,
     back: usize,
 }
 
+#[allow(dead_code)]
 impl<T, const N: usize> DynDeq<T, N> {
     pub fn create() -> Self {
         let size = core::mem::size_of::<Option<T>>() * N;
@@ -197,6 +240,34 @@ mod tests {
         }
     }
 
+    #[test]
+    fn drops_the_elements_it_owns() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Drop for Counted {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let mut dynarray = DynArray::<Counted, 4>::create();
+        dynarray.write_at(0, Counted);
+        dynarray.write_at(2, Counted);
+        dynarray.write_at(2, Counted);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 1);
+        // slots pointing elsewhere belong to someone else
+        let mut foreign = Counted;
+        unsafe { dynarray.as_mut_slice().as_mut().unwrap()[1] = Some(&mut foreign) };
+        let pointers = dynarray.inner();
+        drop(dynarray);
+        assert_eq!(DROPS.load(Ordering::Relaxed), 3);
+        assert_eq!(
+            unsafe { libc::msync(pointers.cast(), PAGE_SIZE, libc::MS_ASYNC) },
+            -1
+        );
+    }
+
     #[test]
     fn is_empty() {
         let dynarray = DynArray::<u32, 4>::create();
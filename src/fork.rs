@@ -0,0 +1,153 @@
+//! Fork handlers for instances that live for the rest of the process. Spans
+//! are mapped shared from the span file, so without them a forked child and
+//! its parent would keep writing into each other's objects.
+
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicI32, AtomicPtr, Ordering},
+};
+
+use spin::{Mutex, Once};
+
+use crate::{
+    rng::{lock_streams, unlock_streams},
+    runtime::Messloc,
+    thread_heap::{lock_retired, unlock_retired},
+    utils::{close, pipe, pthread_atfork, wait_till_memory_ready},
+    MAX_FORK_INSTANCES,
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNREGISTERED: AtomicPtr<Messloc> = AtomicPtr::new(null_mut());
+static INSTANCES: [AtomicPtr<Messloc>; MAX_FORK_INSTANCES] = [UNREGISTERED; MAX_FORK_INSTANCES];
+// held from `prepare` until the parent and the child are done, so the set of
+// instances doesn't change in between
+static FORKING: Mutex<()> = Mutex::new(());
+static HANDLERS: Once<bool> = Once::new();
+// the child closes its end once it stopped sharing pages with the parent
+static READY_READ: AtomicI32 = AtomicI32::new(-1);
+static READY_WRITE: AtomicI32 = AtomicI32::new(-1);
+
+/// Lock `messloc` around every fork and move the child onto a private copy of
+/// its span file. Returns `false` if the handlers can't be installed or every
+/// slot is taken.
+pub fn register(messloc: &'static Messloc) -> bool {
+    let installed = HANDLERS
+        .call_once(|| unsafe { pthread_atfork(Some(prepare), Some(parent), Some(child)) }.is_ok());
+    if !installed {
+        return false;
+    }
+
+    let _forking = FORKING.lock();
+    let messloc = (messloc as *const Messloc).cast_mut();
+    INSTANCES.iter().any(|slot| {
+        match slot.compare_exchange(null_mut(), messloc, Ordering::AcqRel, Ordering::Acquire) {
+            Ok(_) => true,
+            Err(registered) => registered == messloc,
+        }
+    })
+}
+
+fn instances() -> impl Iterator<Item = &'static Messloc> {
+    INSTANCES
+        .iter()
+        .map_while(|slot| unsafe { slot.load(Ordering::Acquire).as_ref() })
+}
+
+/// Quiesce every instance, which also keeps their mesh threads from meshing
+unsafe extern "C" fn prepare() {
+    core::mem::forget(FORKING.lock());
+    instances().for_each(|messloc| core::mem::forget(messloc.0.lock()));
+    lock_retired();
+    lock_streams();
+
+    let mut fds = [-1; 2];
+    if pipe(&mut fds).is_ok() {
+        READY_READ.store(fds[0], Ordering::Release);
+        READY_WRITE.store(fds[1], Ordering::Release);
+    }
+}
+
+unsafe extern "C" fn parent() {
+    let _ = close(READY_WRITE.swap(-1, Ordering::AcqRel));
+    // writes would leak into the child until it has copied the span files.
+    // Only this thread waits, see `Messloc::install_fork_handlers`; without a
+    // pipe there is nothing to wait on
+    let ready = READY_READ.swap(-1, Ordering::AcqRel);
+    if ready >= 0 {
+        wait_till_memory_ready(ready);
+        let _ = close(ready);
+    }
+
+    unlock_streams(false);
+    unlock_retired();
+    instances().for_each(|messloc| messloc.0.force_unlock());
+    FORKING.force_unlock();
+}
+
+/// Runs after the child handlers registered before it, whose heap writes still
+/// land in the parent's span file, see `Messloc::install_fork_handlers`
+unsafe extern "C" fn child() {
+    let _ = close(READY_READ.swap(-1, Ordering::AcqRel));
+    for messloc in instances() {
+        // the child is single threaded, nobody else can hold the lock
+        messloc.0.force_unlock();
+        let mut runtime = messloc.0.lock();
+        if runtime.global_heap.arena.reopen_span_file().is_err() {
+            // carrying on would corrupt both processes
+            libc::abort();
+        }
+
+        // threads don't survive a fork, so they can be started again
+        runtime.mesh_thread = None;
+        if runtime.signal_thread.take().is_some() {
+            let _ = close(runtime.signal_fd);
+        }
+    }
+    let _ = close(READY_WRITE.swap(-1, Ordering::AcqRel));
+
+    // new generators in the child don't repeat the parent's streams
+    unlock_streams(true);
+    unlock_retired();
+    FORKING.force_unlock();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::alloc::Layout;
+
+    #[test]
+    // objects are allocated with the layout of a `u64`
+    #[allow(clippy::cast_ptr_alignment)]
+    fn children_get_their_own_copy_of_the_heap() {
+        let messloc: &'static Messloc = Box::leak(Box::new(Messloc::init()));
+        assert!(messloc.install_fork_handlers());
+        assert!(messloc.install_fork_handlers());
+        assert_eq!(
+            instances().filter(|m| core::ptr::eq(*m, messloc)).count(),
+            1
+        );
+
+        let layout = Layout::new::<u64>();
+        let object = unsafe { messloc.allocate(layout) }.cast::<u64>();
+        unsafe { object.write(7) };
+
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            unsafe {
+                let intact = object.read() == 7;
+                object.write(9);
+                let fresh = messloc.allocate(layout).cast::<u64>();
+                fresh.write(11);
+                libc::_exit(i32::from(!(intact && object.read() == 9)));
+            }
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+        assert_eq!(unsafe { object.read() }, 7);
+        unsafe { messloc.deallocate(object.cast(), layout) };
+    }
+}
@@ -1,24 +1,36 @@
 use core::{
+    fmt,
     ptr::null_mut,
-    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     time::Duration,
 };
 
 use crate::{
-    class_array::CLASS_ARRAY, comparatomic::Comparatomic, fake_std::dynarray::DynArray,
-    meshable_arena::MeshableArena, mini_heap::MiniHeap, one_way_mmap_heap::OneWayMmapHeap,
-    rng::Rng, shuffle_vector::ShuffleVector, MAX_SHUFFLE_VECTOR_LENGTH, MAX_SIZE, MAX_SMALL_SIZE,
-    NUM_BINS, PAGE_SIZE,
+    class_array::{CLASS_ARRAY, CLASS_TO_SIZE},
+    comparatomic::Comparatomic,
+    fake_std::dynarray::DynArray,
+    meshable_arena::MeshableArena,
+    mini_heap::MiniHeap,
+    rng::Rng,
+    shuffle_vector::ShuffleVector,
+    split_mesher::{MeshPairs, SplitMesher},
+    stats::HeapStats,
+    write_barrier::WriteBarrier,
+    MAX_MINI_HEAPS, MAX_SHUFFLE_VECTOR_LENGTH, MAX_SIZE, MAX_SMALL_SIZE, MESH_PERIOD_MS,
+    MIN_OBJECTS_PER_SPAN, NUM_BINS, PAGE_SIZE,
 };
 
 pub struct GlobalHeap {
     pub arena: MeshableArena,
     pub shuffle_vector: DynArray<ShuffleVector<MAX_SHUFFLE_VECTOR_LENGTH>, NUM_BINS>,
     pub rng: Rng,
+    pub mesher: SplitMesher,
     pub last_mesh_effective: AtomicBool,
     pub mesh_period_ms: Duration,
-    pub mini_heap_count: AtomicUsize,
     pub current: u64,
+    pub mesh_passes: usize,
+    pub meshes: usize,
+    pub pages_reclaimed: usize,
 }
 
 impl GlobalHeap {
@@ -28,70 +40,400 @@ impl GlobalHeap {
             arena,
             shuffle_vector: DynArray::create(),
             rng: Rng::init(),
+            mesher: SplitMesher::init(),
             last_mesh_effective: AtomicBool::new(false),
-            mesh_period_ms: Duration::new(0, 0),
-            mini_heap_count: AtomicUsize::new(0),
+            mesh_period_ms: Duration::from_millis(MESH_PERIOD_MS),
             current: 0,
+            mesh_passes: 0,
+            meshes: 0,
+            pages_reclaimed: 0,
         }
     }
 
     /// Allocate a region of memory that can satisfy the requested bytes
     pub fn malloc(&mut self, bytes: usize) -> *const () {
-        if let Some(size_class) = SizeMap.get_size_class(bytes) {
-            let sv = match self.shuffle_vector.get(size_class) {
-                Some(Some(s)) if let Some(sv) = unsafe { s.as_mut() } => {
-                    sv
-                },
-
-                Some(None) => {
-                    self.shuffle_vector.write_at(size_class, ShuffleVector::new());
-                    unsafe { self.shuffle_vector.get(size_class).unwrap().unwrap().as_mut().unwrap() }
-                    },
-
-                _ => {
-                        unreachable!()
-                    }
-                };
+        self.memalign(1, bytes)
+    }
 
-            let allocated = sv.malloc() as *mut ();
+    /// Allocate `bytes` at an address that is a multiple of `align`, which has
+    /// to be a power of two
+    pub fn memalign(&mut self, align: usize, bytes: usize) -> *const () {
+        if let Some(size_class) = SizeMap.get_aligned_size_class(bytes, align) {
+            let sv = self.shuffle_vector_for(size_class);
 
-            //TODO:: Consider which strategy to pick - whether to allocate an entire page and
-            //fragment or do each allocation separately
+            let allocated = sv.malloc();
             if allocated.is_null() {
-                let fresh = unsafe { OneWayMmapHeap.malloc(bytes) as *mut () };
-                let _ = unsafe { self.arena.generate_mini_heap(fresh, bytes) };
-                if self.arena.arena_begin.is_null() {
-                    self.arena.arena_begin = fresh;
-                }
-                fresh
+                self.refill_shuffle_vector(sv, size_class);
+                sv.malloc()
             } else {
                 allocated
             }
         } else {
-            unsafe { self.alloc_page_aligned(1).cast() }
+            let page_count = bytes
+                .checked_add(PAGE_SIZE - 1)
+                .map_or(0, |end| end / PAGE_SIZE);
+            unsafe { self.alloc_page_aligned(page_count, align).as_ref() }
+                .and_then(|heap| heap.malloc_at(0))
+                .unwrap_or(null_mut())
         }
     }
 
     ///# Safety
     /// Unsafe
 
-    pub unsafe fn free(&mut self, ptr: *mut (), bytes: usize) {
-        if let Some(size_class) = SizeMap.get_size_class(bytes) {
-            let shuffle_vectors = self.shuffle_vector.as_mut_slice().as_mut().unwrap();
-            let mh = self.arena.get_mini_heap(ptr).unwrap();
-            match shuffle_vectors.get(size_class) {
-                Some(Some(sv)) if let Some(v) = sv.as_mut() => {
-                    v.mini_heaps.push(mh);
+    pub unsafe fn free(&mut self, ptr: *mut ()) {
+        let Some(mh) = self.arena.get_mini_heap(ptr) else {
+            return;
+        };
+        let heap = mh.as_ref().unwrap();
+        if heap.is_large_alloc() {
+            // a large object owns its whole span, which goes straight back to the arena
+            self.arena.release_mini_heap(mh);
+            return;
+        }
+
+        match SizeMap.get_size_class(heap.object_size) {
+            Some(size_class)
+                if heap.is_attached() && self.shuffle_vector_for(size_class).free(mh, ptr) =>
+            {
+                return;
+            }
+            _ => {}
+        }
+
+        heap.free_offset(heap.offset_for(ptr));
+        if !heap.is_attached() && heap.is_empty() {
+            self.arena.release_mini_heap(mh);
+        }
+    }
+
+    /// Resize the large allocation at `ptr` to `bytes` in place: the tail of
+    /// its span is handed back when shrinking, and when growing the span is
+    /// extended onto the following pages of the arena. Returns `None` if those
+    /// are taken and `ptr` has to be moved by the caller instead.
+    ///# Safety
+    /// `ptr` must be a live allocation of this heap
+    pub unsafe fn resize_large(&mut self, ptr: *mut (), bytes: usize) -> Option<*mut ()> {
+        let mh = self.arena.get_mini_heap(ptr)?;
+        let heap = mh.as_mut().unwrap();
+        let page_count = bytes.checked_add(PAGE_SIZE - 1)? / PAGE_SIZE;
+        if !heap.is_large_alloc() || ptr != heap.arena_begin.cast() || page_count == 0 {
+            return None;
+        }
+
+        let tail = ptr
+            .cast::<u8>()
+            .add(page_count.min(heap.span_pages) * PAGE_SIZE);
+        if page_count < heap.span_pages {
+            self.arena
+                .shrink_span(heap.span_offset, heap.span_pages, page_count);
+            self.arena
+                .track_span(tail.cast(), heap.span_pages - page_count, None);
+        } else if page_count > heap.span_pages {
+            if !self
+                .arena
+                .grow_span(heap.span_offset, heap.span_pages, page_count)
+            {
+                return None;
+            }
+            self.arena
+                .track_span(tail.cast(), page_count - heap.span_pages, Some(mh));
+        }
+
+        heap.span_pages = page_count;
+        heap.object_size = heap.span_size();
+        Some(ptr)
+    }
+
+    #[allow(clippy::mut_from_ref)]
+    fn shuffle_vector_for(
+        &mut self,
+        size_class: usize,
+    ) -> &'static mut ShuffleVector<MAX_SHUFFLE_VECTOR_LENGTH> {
+        match self.shuffle_vector.get(size_class) {
+            Some(Some(s)) if let Some(sv) = unsafe { s.as_mut() } => sv,
+
+            Some(None) => {
+                self.shuffle_vector
+                    .write_at(size_class, ShuffleVector::new());
+                unsafe {
+                    self.shuffle_vector
+                        .get(size_class)
+                        .unwrap()
+                        .unwrap()
+                        .as_mut()
+                        .unwrap()
                 }
-                _ => todo!(),
             }
-        } else {
-            unreachable!()
+
+            _ => {
+                unreachable!()
+            }
+        }
+    }
+
+    /// Detach exhausted `MiniHeap`s from the shuffle vector and attach partially
+    /// used or fresh ones until it is full again
+    pub fn refill_shuffle_vector(
+        &mut self,
+        sv: &mut ShuffleVector<MAX_SHUFFLE_VECTOR_LENGTH>,
+        size_class: usize,
+    ) {
+        let arena = &mut self.arena;
+        sv.detach_full(|mh| unsafe {
+            if mh.as_ref().unwrap().is_empty() {
+                arena.release_mini_heap(mh);
+            }
+        });
+
+        loop {
+            sv.refill();
+            if sv.is_full() {
+                break;
+            }
+
+            let mh = self
+                .find_partial_mini_heap(size_class)
+                .unwrap_or_else(|| self.alloc_small_mini_heap(size_class));
+            if mh.is_null() || !sv.insert(mh) {
+                break;
+            }
+        }
+    }
+
+    /// Find a `MiniHeap` of the given size class that still has free slots
+    fn find_partial_mini_heap(&self, size_class: usize) -> Option<*mut MiniHeap> {
+        let object_size = SizeMap.class_to_size(size_class);
+        self.arena.iter_mini_heaps().find(|mh| {
+            let mh = unsafe { mh.as_ref().unwrap() };
+            mh.object_size == object_size
+                && !mh.is_large_alloc()
+                && !mh.is_full()
+                && !mh.is_attached()
+        })
+    }
+
+    /// Carve a fresh span into a `MiniHeap` for the given size class
+    fn alloc_small_mini_heap(&mut self, size_class: usize) -> *mut MiniHeap {
+        let object_size = SizeMap.class_to_size(size_class);
+        let page_count = SizeMap.page_count(size_class);
+
+        let Some(span) = self.arena.alloc_span(page_count) else {
+            return null_mut();
+        };
+        let mh = unsafe { self.arena.generate_mini_heap(span, object_size) };
+        if mh.is_null() {
+            self.arena.free_pages(span.offset, span.length);
+        }
+        mh
+    }
+
+    /// Find mesh candidates across every size class and mesh them, returning
+    /// how many meshes were performed. Only empty heaps are released if the
+    /// arena's backing can't be meshed.
+    pub fn mesh_pass(&mut self) -> usize {
+        self.mesh_passes += 1;
+        let released = self.release_empty_mini_heaps();
+        if !self.arena.can_mesh() {
+            self.last_mesh_effective
+                .store(released > 0, Ordering::Release);
+            return 0;
+        }
+
+        let mut pairs = MeshPairs::new();
+        self.mesher
+            .find_pairs(&self.arena, &mut self.rng, &mut pairs);
+
+        let meshed = pairs
+            .iter()
+            .filter(|pair| unsafe { self.mesh(pair.dst, pair.src) })
+            .count();
+        self.last_mesh_effective
+            .store(meshed + released > 0, Ordering::Release);
+        meshed
+    }
+
+    /// Release detached heaps without live objects. These are left behind by
+    /// exiting threads, which detach their heaps without taking the lock.
+    fn release_empty_mini_heaps(&mut self) -> usize {
+        let mut released = 0;
+        for index in 0..MAX_MINI_HEAPS {
+            match self.arena.mini_heaps.get(index) {
+                Some(Some(mh))
+                    if unsafe { mh.as_ref() }
+                        .map_or(false, |heap| !heap.is_attached() && heap.is_empty()) =>
+                {
+                    unsafe { self.arena.release_mini_heap(mh) };
+                    released += 1;
+                }
+                _ => {}
+            }
+        }
+        released
+    }
+
+    /// Merge `src` into `dst`: live objects of `src` are copied to the same
+    /// offsets in `dst`, after which every virtual span of `src` is remapped onto
+    /// the physical pages of `dst` and the memory of `src` is discarded. Its
+    /// pages stay claimed by its virtual spans until `dst` is released. Returns
+    /// `false` if the two heaps cannot be meshed.
+    ///# Safety
+    /// `dst` and `src` must be distinct heaps owned by this global heap
+    pub unsafe fn mesh(&mut self, dst: *mut MiniHeap, src: *mut MiniHeap) -> bool {
+        let (dst_heap, src_heap) = (dst.as_mut().unwrap(), src.as_ref().unwrap());
+        if dst == src
+            || !self.arena.can_mesh()
+            || dst_heap.is_attached()
+            || src_heap.is_attached()
+            || !dst_heap.is_meshable_with(src_heap)
+        {
+            return false;
+        }
+
+        // writes to `src` are held off until its spans point at `dst`
+        let Some(barrier) = WriteBarrier::protect(src_heap.spans(), src_heap.span_size()) else {
+            return false;
+        };
+        let moved = || (0..src_heap.object_count).filter(|offset| src_heap.bitmap.is_set(*offset));
+        moved().for_each(|offset| {
+            let target = dst_heap.malloc_at(offset).unwrap();
+            core::ptr::copy_nonoverlapping(
+                src_heap.ptr_for(offset).cast::<u8>(),
+                target.cast::<u8>(),
+                src_heap.object_size,
+            );
+        });
+
+        // remapping fails with ENOMEM once the arena is split into too many
+        // mappings, in which case the mesh is rolled back
+        let (offset, pages) = (dst_heap.span_offset, dst_heap.span_pages);
+        let remapped = src_heap
+            .spans()
+            .take_while(|begin| self.arena.remap(begin.cast(), offset, pages).is_ok())
+            .count();
+        if remapped < src_heap.spans().count() {
+            let restored = src_heap
+                .spans()
+                .take(remapped)
+                .fold(true, |restored, begin| {
+                    let back =
+                        self.arena
+                            .remap(begin.cast(), src_heap.span_offset, src_heap.span_pages);
+                    restored && back.is_ok()
+                });
+            // a span still pointing at `dst` keeps the copied objects claimed
+            if restored {
+                moved().for_each(|offset| dst_heap.free_offset(offset));
+            }
+            // dropping the barrier makes the spans writable again
+            drop(barrier);
+            return false;
+        }
+
+        for begin in src_heap.spans() {
+            self.arena
+                .track_span(begin.cast(), dst_heap.span_pages, Some(dst));
+            dst_heap.meshed_spans[dst_heap.mesh_count] = begin;
+            dst_heap.mesh_count += 1;
+        }
+        drop(barrier);
+
+        // the virtual spans of `src` keep its pages claimed, but their memory
+        // can go back right away
+        self.arena
+            .discard_pages(src_heap.span_offset, src_heap.span_pages);
+        self.meshes += 1;
+        self.pages_reclaimed += src_heap.span_pages;
+        self.arena.forget_mini_heap(src);
+        true
+    }
+
+    /// Write a human readable summary of the heap to `out`: the number of
+    /// spans and live objects for every size class in use
+    ///# Errors
+    /// Fails if `out` runs out of space
+    pub fn dump_stats(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(
+            out,
+            "messloc: {} mini heaps, {} pages in use",
+            self.arena.mini_heap_count(),
+            self.arena.pages_in_use()
+        )?;
+
+        let heaps = || {
+            self.arena
+                .iter_mini_heaps()
+                .filter_map(|mh| unsafe { mh.as_ref() })
+        };
+        for object_size in CLASS_TO_SIZE.iter().skip(1) {
+            let (spans, meshed, live) = heaps()
+                .filter(|heap| !heap.is_large_alloc())
+                .filter(|heap| heap.object_size == *object_size as usize)
+                .fold((0, 0, 0), |(spans, meshed, live), heap| {
+                    (
+                        spans + 1,
+                        meshed + heap.mesh_count,
+                        live + heap.in_use_count(),
+                    )
+                });
+            if spans > 0 {
+                writeln!(
+                    out,
+                    "  {object_size:>5}B: {spans} spans, {meshed} meshed, {live} live objects"
+                )?;
+            }
+        }
+
+        // large objects can have a class' size, but own their whole span
+        let (spans, pages) = heaps()
+            .filter(|heap| heap.is_large_alloc())
+            .fold((0, 0), |(spans, pages), heap| {
+                (spans + 1, pages + heap.span_pages)
+            });
+        if spans > 0 {
+            writeln!(out, "  large: {spans} spans, {pages} pages")?;
         }
+        Ok(())
     }
 
-    /// Allocate the requested number of pages
-    unsafe fn alloc_page_aligned(&mut self, page_count: usize) -> *mut MiniHeap {
+    /// Snapshot of the memory held by the heap's spans and of what meshing
+    /// reclaimed so far
+    pub fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            pages_reserved: self.arena.pages_in_use(),
+            mesh_passes: self.mesh_passes,
+            meshes: self.meshes,
+            pages_reclaimed: self.pages_reclaimed,
+            ..HeapStats::default()
+        };
+
+        for heap in self
+            .arena
+            .iter_mini_heaps()
+            .filter_map(|mh| unsafe { mh.as_ref() })
+        {
+            stats.pages_committed += heap.span_pages;
+            let size_class = SizeMap.get_size_class(heap.object_size);
+            match size_class {
+                Some(size_class) if !heap.is_large_alloc() => {
+                    let class = &mut stats.size_classes[size_class];
+                    class.object_size = heap.object_size;
+                    class.live_objects += heap.in_use_count();
+                    class.allocated_bytes += heap.in_use_count() * heap.object_size;
+                    class.spans += 1;
+                    class.meshed_spans += heap.mesh_count;
+                }
+                _ => {
+                    stats.large.objects += heap.in_use_count();
+                    stats.large.allocated_bytes += heap.in_use_count() * heap.span_size();
+                }
+            }
+        }
+        stats
+    }
+
+    /// Allocate the requested number of pages, starting at a multiple of `align`
+    unsafe fn alloc_page_aligned(&mut self, page_count: usize, align: usize) -> *mut MiniHeap {
         // if given a very large allocation size (e.g. (usize::MAX)-8), it is possible
         // the pages calculation overflowed. An allocation that big is impossible
         // to satisfy anyway, so just fail early.
@@ -99,39 +441,33 @@ impl GlobalHeap {
             return null_mut();
         }
 
-        self.alloc_miniheap(page_count)
-
-        //   d_assert(mh->isLargeAlloc());
-        //   d_assert(mh->spanSize() == pageCount * kPageSize);
-        //   // d_assert(mh->objectSize() == pageCount * kPageSize);
-        //
+        let mh = self.alloc_miniheap(page_count, align);
+        debug_assert!(mh.as_ref().map_or(true, MiniHeap::is_large_alloc));
+        mh
     }
 
-    fn alloc_miniheap(&mut self, page_count: usize) -> *mut MiniHeap {
+    /// Map a span of `page_count` pages out of the arena and record it as a
+    /// `MiniHeap` holding a single object that covers the whole span
+    fn alloc_miniheap(&mut self, page_count: usize, align: usize) -> *mut MiniHeap {
         debug_assert!(page_count > 0, "should allocate at least 1 page");
 
-        let page = unsafe { OneWayMmapHeap.malloc(PAGE_SIZE) } as *mut ();
-
-        let buf =
-            unsafe { OneWayMmapHeap.malloc(core::mem::size_of::<MiniHeap>()) } as *mut MiniHeap;
-        // allocate out of the arena
-        // TODO: Check if we need this since it doesn't match the current lazy model
-
-        //TODO: Adjust value of span by going through find_pages on the arena and the related code
-        unsafe {
-            buf.write(MiniHeap::new(page, PAGE_SIZE));
+        let Some(span) = self.arena.alloc_aligned_span(page_count, align) else {
+            return null_mut();
+        };
+        let mh = unsafe { self.arena.generate_mini_heap(span, span.byte_length()) };
+        if mh.is_null() {
+            // out of metadata slots, hand the span back
+            self.arena.free_pages(span.offset, span.length);
+            return null_mut();
         }
 
-        // // mesh::debug("%p (%u) created!\n", mh, GetMiniHeapID(mh));
-
-        self.mini_heap_count.fetch_add(1, Ordering::AcqRel);
-        buf
+        mh
     }
 }
 
 unsafe impl Send for GlobalHeap {}
 
-trait Meshable {
+pub trait Meshable {
     fn is_meshable(&self, other: &Self) -> bool;
 }
 
@@ -144,7 +480,20 @@ impl<const N: usize> Meshable for [Comparatomic<AtomicU64>; N] {
     }
 }
 
-struct SizeMap;
+/// Number of bytes an allocation of `size` bytes actually gets: the object
+/// size of its size class, or whole pages for large allocations. Returns
+/// `None` if `size` can't be allocated at all.
+#[must_use]
+pub fn size_class_for(size: usize) -> Option<usize> {
+    match SizeMap.get_size_class(size) {
+        Some(size_class) => Some(SizeMap.class_to_size(size_class)),
+        None => size
+            .checked_add(PAGE_SIZE - 1)
+            .map(|end| end & !(PAGE_SIZE - 1)),
+    }
+}
+
+pub struct SizeMap;
 
 impl SizeMap {
     pub fn get_size_class(&self, size: usize) -> Option<usize> {
@@ -152,6 +501,27 @@ impl SizeMap {
         Some(CLASS_ARRAY[idx] as usize)
     }
 
+    /// Smallest size class whose objects all start at a multiple of `align`.
+    /// Spans are page aligned, so this holds for classes whose object size is a
+    /// multiple of `align`; larger alignments need a large allocation.
+    pub fn get_aligned_size_class(&self, size: usize, align: usize) -> Option<usize> {
+        if align > PAGE_SIZE {
+            return None;
+        }
+        let first = self.get_size_class(size.max(align))?;
+        (first..NUM_BINS).find(|size_class| self.class_to_size(*size_class) % align == 0)
+    }
+
+    #[allow(clippy::unused_self)]
+    pub fn class_to_size(&self, size_class: usize) -> usize {
+        CLASS_TO_SIZE[size_class] as usize
+    }
+
+    /// Number of pages backing a span of the given size class
+    pub fn page_count(&self, size_class: usize) -> usize {
+        (self.class_to_size(size_class) * MIN_OBJECTS_PER_SPAN + PAGE_SIZE - 1) / PAGE_SIZE
+    }
+
     #[allow(clippy::unused_self)]
     const fn class_index_maybe(&self, size: usize) -> Option<usize> {
         // this is overlapping but allowed because it currently is the nicest way
@@ -164,3 +534,216 @@ impl SizeMap {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dumps_report_large_spans_apart_from_their_class() {
+        let mut heap = GlobalHeap::init();
+        let small = heap.memalign(8, 4096);
+        let large = heap.memalign(8 * PAGE_SIZE, 4096);
+        let mh = unsafe { heap.arena.get_mini_heap(large.cast_mut()) }.unwrap();
+        let large_heap = unsafe { mh.as_ref() }.unwrap();
+        assert!(large_heap.is_large_alloc() && large_heap.object_size == 4096);
+
+        let mut report = String::new();
+        heap.dump_stats(&mut report).unwrap();
+        // every other span belongs to the 4096 byte class
+        let class_spans = heap.arena.mini_heap_count() - 1;
+        assert!(report.contains(&format!("   4096B: {class_spans} spans, 0 meshed")));
+        assert!(report.ends_with("  large: 1 spans, 1 pages\n"));
+        unsafe { heap.free(small.cast_mut()) };
+    }
+
+    #[test]
+    fn small_allocations_come_from_a_shared_span() {
+        let mut heap = GlobalHeap::init();
+        let first = heap.malloc(48).cast_mut();
+        let second = heap.malloc(40).cast_mut();
+
+        assert_ne!(first, second);
+        assert_eq!(heap.arena.mini_heap_count(), 1);
+        let mh = unsafe { heap.arena.get_mini_heap(first) }.unwrap();
+        assert_eq!(unsafe { heap.arena.get_mini_heap(second) }, Some(mh));
+
+        // freed objects go back into the shuffle vector, so their slots stay reserved
+        let reserved = unsafe { mh.as_ref().unwrap() }.in_use_count();
+        unsafe {
+            heap.free(first);
+            heap.free(second);
+        }
+        assert_eq!(unsafe { mh.as_ref().unwrap() }.in_use_count(), reserved);
+        assert_eq!(heap.arena.mini_heap_count(), 1);
+    }
+
+    #[test]
+    fn meshing_keeps_objects_reachable_through_both_spans() {
+        let mut heap = GlobalHeap::init();
+        let size_class = SizeMap.get_size_class(256).unwrap();
+        let dst = heap.alloc_small_mini_heap(size_class);
+        let src = heap.alloc_small_mini_heap(size_class);
+        let (dst_heap, src_heap) = unsafe { (dst.as_ref().unwrap(), src.as_ref().unwrap()) };
+
+        let kept = dst_heap.malloc_at(0).unwrap().cast::<u64>();
+        let moved = src_heap.malloc_at(1).unwrap().cast::<u64>();
+        unsafe {
+            kept.write(7);
+            moved.write(42);
+        }
+
+        assert!(unsafe { heap.mesh(dst, src) });
+        assert_eq!(heap.arena.mini_heap_count(), 1);
+        assert_eq!(unsafe { heap.arena.get_mini_heap(moved.cast()) }, Some(dst));
+        assert_eq!(dst_heap.in_use_count(), 2);
+
+        // both virtual spans now share the same physical pages
+        unsafe {
+            assert_eq!(moved.read(), 42);
+            assert_eq!(dst_heap.ptr_for(1).cast::<u64>().read(), 42);
+            moved.write(43);
+            assert_eq!(dst_heap.ptr_for(1).cast::<u64>().read(), 43);
+            assert_eq!(src_heap_alias(dst_heap, 0).read(), 7);
+        }
+
+        // the same offset is now taken, so a heap occupying it cannot be meshed
+        let other = heap.alloc_small_mini_heap(size_class);
+        unsafe { other.as_ref().unwrap() }.malloc_at(1).unwrap();
+        assert!(!unsafe { heap.mesh(dst, other) });
+    }
+
+    #[test]
+    fn failed_remaps_roll_the_mesh_back() {
+        let mut heap = GlobalHeap::init();
+        let size_class = SizeMap.get_size_class(256).unwrap();
+        let dst = heap.alloc_small_mini_heap(size_class);
+        let src = heap.alloc_small_mini_heap(size_class);
+        let (dst_heap, src_heap) = unsafe { (dst.as_ref().unwrap(), src.as_ref().unwrap()) };
+        let moved = src_heap.malloc_at(1).unwrap().cast::<u64>();
+        unsafe { moved.write(42) };
+
+        // without a span file every remap fails
+        let fd = core::mem::replace(&mut heap.arena.fd, -1);
+        assert!(!unsafe { heap.mesh(dst, src) });
+        heap.arena.fd = fd;
+
+        assert!(dst_heap.is_empty());
+        assert_eq!(dst_heap.mesh_count, 0);
+        assert_eq!(unsafe { heap.arena.get_mini_heap(moved.cast()) }, Some(src));
+        // the span is writable again and still holds the object
+        unsafe { moved.write(moved.read() + 1) };
+        assert_eq!(unsafe { moved.read() }, 43);
+        assert!(unsafe { heap.mesh(dst, src) });
+    }
+
+    #[test]
+    fn mesh_pass_merges_sparse_heaps() {
+        let mut heap = GlobalHeap::init();
+        let size_class = SizeMap.get_size_class(1024).unwrap();
+        let heaps: [*mut MiniHeap; 4] =
+            core::array::from_fn(|_| heap.alloc_small_mini_heap(size_class));
+        heaps.iter().enumerate().for_each(|(k, mh)| {
+            unsafe { mh.as_ref().unwrap() }.malloc_at(k).unwrap();
+        });
+
+        heaps.iter().for_each(|mh| unsafe {
+            let span = mh.as_ref().unwrap();
+            span.arena_begin
+                .cast::<u8>()
+                .write_bytes(1, span.span_size());
+        });
+        let fd = heap.arena.fd;
+        let file_bytes = || {
+            let mut stat = core::mem::MaybeUninit::<libc::stat>::zeroed();
+            unsafe { libc::fstat(fd, stat.as_mut_ptr()) };
+            unsafe { stat.assume_init() }.st_blocks * 512
+        };
+        let before = file_bytes();
+
+        assert_eq!(heap.mesh_pass(), 2);
+        assert!(heap.last_mesh_effective.load(Ordering::Acquire));
+        assert_eq!(heap.arena.mini_heap_count(), 2);
+        let stats = heap.stats();
+        let page_count = SizeMap.page_count(size_class);
+        assert_eq!((stats.mesh_passes, stats.meshes), (1, 2));
+        assert_eq!(stats.pages_reclaimed, 2 * page_count);
+        assert_eq!(stats.pages_committed, 2 * page_count);
+        assert_eq!(stats.pages_reserved, 4 * page_count);
+        assert_eq!(stats.size_classes[size_class].spans, 2);
+        assert_eq!(stats.size_classes[size_class].meshed_spans, 2);
+        assert_eq!(stats.size_classes[size_class].live_objects, 4);
+        // the sources' memory is punched out of the span file
+        let span_bytes = SizeMap.page_count(size_class) * PAGE_SIZE;
+        assert_eq!(
+            before - file_bytes(),
+            2 * i64::try_from(span_bytes).unwrap()
+        );
+    }
+
+    #[test]
+    fn writes_during_a_mesh_are_not_lost() {
+        let mut heap = GlobalHeap::init();
+        let size_class = SizeMap.get_size_class(256).unwrap();
+        let dst = heap.alloc_small_mini_heap(size_class);
+        let src = heap.alloc_small_mini_heap(size_class);
+        unsafe { dst.as_ref().unwrap() }.malloc_at(0).unwrap();
+        let object = unsafe { src.as_ref().unwrap() }.malloc_at(1).unwrap() as usize;
+
+        let done = std::sync::Arc::new(core::sync::atomic::AtomicBool::new(false));
+        let writer = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut value = 0u64;
+                while !done.load(Ordering::Acquire) {
+                    value += 1;
+                    unsafe { (object as *mut u64).write_volatile(value) };
+                }
+                value
+            })
+        };
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        assert!(unsafe { heap.mesh(dst, src) });
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        done.store(true, Ordering::Release);
+        let last = writer.join().unwrap();
+        assert_eq!(unsafe { (object as *const u64).read_volatile() }, last);
+    }
+
+    #[test]
+    fn size_class_for_rounds_to_the_slot_size() {
+        assert_eq!(size_class_for(1), Some(16));
+        assert_eq!(size_class_for(100), Some(112));
+        assert_eq!(size_class_for(MAX_SIZE), Some(MAX_SIZE));
+        assert_eq!(size_class_for(MAX_SIZE + 1), Some(MAX_SIZE + PAGE_SIZE));
+        assert_eq!(size_class_for(usize::MAX), None);
+    }
+
+    #[test]
+    fn large_allocations_get_a_span_of_their_own() {
+        let mut heap = GlobalHeap::init();
+        let bytes = 100_000;
+        let first = heap.malloc(bytes) as *mut u8;
+        let second = heap.malloc(MAX_SIZE + 1) as *mut u8;
+        assert!(!first.is_null() && !second.is_null());
+
+        let mh = unsafe { heap.arena.get_mini_heap(first.add(bytes - 1).cast()) }.unwrap();
+        let large = unsafe { mh.as_ref().unwrap() };
+        assert!(large.is_large_alloc());
+        assert_eq!(large.span_pages, (bytes + PAGE_SIZE - 1) / PAGE_SIZE);
+        unsafe { first.write_bytes(0xab, bytes) };
+        assert!(!large.contains(second.cast()));
+        assert_eq!(heap.arena.pages_in_use(), 25 + 5);
+
+        unsafe { heap.free(first.cast()) };
+        assert_eq!(unsafe { heap.arena.get_mini_heap(first.cast()) }, None);
+        assert_eq!(heap.arena.pages_in_use(), 5);
+        assert!(heap.malloc(usize::MAX - 8).is_null());
+    }
+
+    fn src_heap_alias(heap: &MiniHeap, offset: usize) -> *mut u64 {
+        let begin = heap.meshed_spans[0].cast::<u8>();
+        unsafe { begin.add(offset * heap.object_size) }.cast()
+    }
+}
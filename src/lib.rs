@@ -6,7 +6,6 @@
 )]
 #![allow(clippy::needless_for_each)]
 #![allow(clippy::module_name_repetitions)]
-#![feature(allocator_api)]
 #![feature(let_chains)]
 #![feature(maybe_uninit_uninit_array)]
 #![feature(maybe_uninit_array_assume_init)]
@@ -19,15 +18,26 @@ use core::alloc::{GlobalAlloc, Layout};
 
 use once_cell::sync::OnceCell;
 
-pub use crate::runtime::Messloc;
+pub use crate::{
+    global_heap::size_class_for,
+    runtime::Messloc,
+    stats::{HeapStats, LargeStats, SizeClassStats},
+};
 
-#[cfg(feature = "allocator_api")]
-use core::alloc::{AllocError, Allocator};
+#[cfg(feature = "allocator-api")]
+use core::{
+    alloc::{AllocError, Allocator},
+    ptr::NonNull,
+};
 
 mod arena_fs;
+mod bitmap;
+#[cfg(feature = "c-api")]
+mod c_api;
 mod class_array;
 mod comparatomic;
 mod fake_std;
+mod fork;
 mod global_heap;
 mod meshable_arena;
 mod mini_heap;
@@ -35,7 +45,11 @@ mod one_way_mmap_heap;
 mod rng;
 mod runtime;
 mod shuffle_vector;
+mod split_mesher;
+mod stats;
+mod thread_heap;
 mod utils;
+mod write_barrier;
 
 const PAGE_SIZE: usize = 4096;
 const MAX_SMALL_SIZE: usize = 1024;
@@ -43,6 +57,16 @@ const MAX_SIZE: usize = 16384;
 const NUM_BINS: usize = 25;
 const MAX_SHUFFLE_VECTOR_LENGTH: usize = 64;
 const MAX_MINI_HEAPS_PER_SHUFFLE_VECTOR: usize = 24;
+const MAX_OBJECTS_PER_MINI_HEAP: usize = 256;
+const MIN_OBJECTS_PER_SPAN: usize = 8;
+const MAX_MINI_HEAPS: usize = 16384;
+const MAX_MESHES: usize = 4;
+const ARENA_SIZE: usize = 64 << 30;
+const MAX_MESHES_PER_PASS: usize = 256;
+const SPLIT_MESHER_PROBES: usize = 64;
+const MESH_PERIOD_MS: u64 = 100;
+const MAX_MESH_BACKOFF: u32 = 64;
+const MAX_FORK_INSTANCES: usize = 16;
 
 unsafe impl GlobalAlloc for Messloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
@@ -55,6 +79,81 @@ unsafe impl GlobalAlloc for Messloc {
 
         self.deallocate(ptr, layout);
     }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        self.allocate_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.reallocate(ptr, layout, new_size)
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+impl Messloc {
+    fn allocation(ptr: *mut u8, size: usize) -> Result<NonNull<[u8]>, AllocError> {
+        let slice = core::ptr::slice_from_raw_parts_mut(ptr, size);
+        NonNull::new(slice).ok_or(AllocError)
+    }
+
+    /// Resize through `reallocate` if the alignment stays the same, otherwise
+    /// move the object to a fresh allocation
+    unsafe fn resize(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        if old_layout.align() == new_layout.align() {
+            let new_ptr = self.reallocate(ptr.as_ptr(), old_layout, new_layout.size());
+            return Self::allocation(new_ptr, new_layout.size());
+        }
+
+        let new_ptr = Self::allocation(self.allocate(new_layout), new_layout.size())?;
+        core::ptr::copy_nonoverlapping(
+            ptr.as_ptr(),
+            new_ptr.cast::<u8>().as_ptr(),
+            old_layout.size().min(new_layout.size()),
+        );
+        self.deallocate(ptr.as_ptr(), old_layout);
+        Ok(new_ptr)
+    }
+}
+
+#[cfg(feature = "allocator-api")]
+unsafe impl Allocator for Messloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Self::allocation(unsafe { Messloc::allocate(self, layout) }, layout.size())
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Self::allocation(
+            unsafe { Messloc::allocate_zeroed(self, layout) },
+            layout.size(),
+        )
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Messloc::deallocate(self, ptr.as_ptr(), layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        self.resize(ptr, old_layout, new_layout)
+    }
 }
 
 pub struct MessyLock(pub once_cell::sync::OnceCell<Messloc>);
@@ -82,8 +181,90 @@ unsafe impl GlobalAlloc for MessyLock {
             unreachable!()
         }
     }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if once_cell::sync::OnceCell::get(&self.0).is_none() {
+            self.init_in_place();
+        }
+        OnceCell::get(&self.0).unwrap().allocate_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match once_cell::sync::OnceCell::get(&self.0) {
+            Some(lazy) => lazy.reallocate(ptr, layout, new_size),
+            None => unreachable!(),
+        }
+    }
+}
+
+impl MessyLock {
+    /// See [`Messloc::start_mesh_thread`]
+    pub fn start_mesh_thread(&'static self) -> bool {
+        if OnceCell::get(&self.0).is_none() {
+            self.init_in_place();
+        }
+        OnceCell::get(&self.0).map_or(false, Messloc::start_mesh_thread)
+    }
+
+    /// See [`Messloc::start_signal_listener`]
+    pub fn start_signal_listener(&'static self) -> bool {
+        if OnceCell::get(&self.0).is_none() {
+            self.init_in_place();
+        }
+        OnceCell::get(&self.0).map_or(false, Messloc::start_signal_listener)
+    }
+
+    /// See [`Messloc::install_fork_handlers`]
+    pub fn install_fork_handlers(&'static self) -> bool {
+        if OnceCell::get(&self.0).is_none() {
+            self.init_in_place();
+        }
+        OnceCell::get(&self.0).map_or(false, Messloc::install_fork_handlers)
+    }
 }
 
 impl Drop for MessyLock {
     fn drop(&mut self) {}
 }
+
+#[cfg(feature = "allocator-api")]
+impl MessyLock {
+    fn messloc(&self) -> &Messloc {
+        self.0.get_or_init(Messloc::init)
+    }
+}
+
+/// Forwards to the lazily initialized [`Messloc`], so a `&'static MessyLock`
+/// can be handed to collections like `Vec::new_in`
+#[cfg(feature = "allocator-api")]
+unsafe impl Allocator for MessyLock {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate(self.messloc(), layout)
+    }
+
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::allocate_zeroed(self.messloc(), layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Allocator::deallocate(self.messloc(), ptr, layout);
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::grow(self.messloc(), ptr, old_layout, new_layout)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, AllocError> {
+        Allocator::shrink(self.messloc(), ptr, old_layout, new_layout)
+    }
+}
@@ -1,77 +1,844 @@
-use crate::one_way_mmap_heap::OneWayMmapHeap;
-use crate::NUM_BINS;
+use crate::arena_fs::{open_span_file, report_fallback, Backing};
+use crate::one_way_mmap_heap::Mapping;
+use crate::utils::{close, fallocate, madvise, mmap, mmap_anonymous, munmap, Result};
 use crate::{fake_std::dynarray::DynArray, mini_heap::MiniHeap, PAGE_SIZE};
-use core::ptr::null_mut;
+use crate::{ARENA_SIZE, MAX_MINI_HEAPS};
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicU32, Ordering},
+};
 pub type Page = [u8; PAGE_SIZE];
 
+/// A run of pages of the span file and the address it is mapped at
+#[derive(Clone, Copy, Debug)]
+pub struct Span {
+    pub begin: *mut (),
+    /// page offset inside the span file
+    pub offset: usize,
+    /// length in pages
+    pub length: usize,
+    /// whether every page is known to be zero
+    pub zeroed: bool,
+}
+
+impl Span {
+    pub const fn new(begin: *mut (), offset: usize, length: usize) -> Self {
+        Self {
+            begin,
+            offset,
+            length,
+            zeroed: false,
+        }
+    }
+
+    pub const fn byte_offset(&self) -> usize {
+        self.offset * PAGE_SIZE
+    }
+
+    pub const fn byte_length(&self) -> usize {
+        self.length * PAGE_SIZE
+    }
+}
+
+/// Length and list links of a free run, kept at its first page. The last page
+/// of the run repeats the length so that the run can be found from behind.
+#[derive(Clone, Copy)]
+struct FreeRun {
+    len: u32,
+    next: u32,
+    prev: u32,
+}
+
+const NO_RUN: u32 = u32::MAX;
+/// Free runs are bucketed by the highest set bit of their length
+const RUN_BUCKETS: usize = 32;
+
+/// Page granular allocator, one bit per page of the span file. The free pages
+/// below `end` are kept as maximal runs in size bucketed lists.
+struct PageMap {
+    used: Mapping,
+    /// pages that have been handed out at some point and may hold stale data,
+    /// the others still read as zero
+    dirty: Mapping,
+    runs: Mapping,
+    heads: [u32; RUN_BUCKETS],
+    capacity: usize,
+    // pages from `end` on are free and in no run, so no run ends at `end`
+    end: usize,
+    freed: usize,
+}
+
+impl PageMap {
+    fn init(capacity: usize) -> Self {
+        let size = (capacity + 63) / 64 * core::mem::size_of::<u64>();
+        Self {
+            used: Mapping::new(size),
+            dirty: Mapping::new(size),
+            runs: Mapping::new(capacity * core::mem::size_of::<FreeRun>()),
+            heads: [NO_RUN; RUN_BUCKETS],
+            capacity,
+            end: 0,
+            freed: 0,
+        }
+    }
+
+    fn word(map: *mut u64, page: usize) -> *mut u64 {
+        unsafe { map.add(page / 64) }
+    }
+
+    fn test(map: *mut u64, page: usize) -> bool {
+        unsafe { *Self::word(map, page) & (1 << (page % 64)) != 0 }
+    }
+
+    fn set(map: *mut u64, start: usize, count: usize, value: bool) {
+        (start..start + count).for_each(|page| unsafe {
+            if value {
+                *Self::word(map, page) |= 1 << (page % 64);
+            } else {
+                *Self::word(map, page) &= !(1 << (page % 64));
+            }
+        });
+    }
+
+    fn is_used(&self, page: usize) -> bool {
+        Self::test(self.used.as_ptr(), page)
+    }
+
+    fn mark(&mut self, start: usize, count: usize, used: bool) {
+        Self::set(self.used.as_ptr(), start, count, used);
+    }
+
+    /// Whether none of the pages may hold stale data
+    fn is_clean(&self, start: usize, count: usize) -> bool {
+        (start..start + count).all(|page| !Self::test(self.dirty.as_ptr(), page))
+    }
+
+    fn mark_dirty(&mut self, start: usize, count: usize, dirty: bool) {
+        Self::set(self.dirty.as_ptr(), start, count, dirty);
+    }
+
+    /// Smallest page index from `page` on that is `phase` modulo `step`
+    const fn align_up(page: usize, step: usize, phase: usize) -> usize {
+        page + (phase + step - page % step) % step
+    }
+
+    fn run(&self, page: usize) -> *mut FreeRun {
+        unsafe { self.runs.as_ptr::<FreeRun>().add(page) }
+    }
+
+    const fn bucket(len: usize) -> usize {
+        (usize::BITS - 1 - len.leading_zeros()) as usize
+    }
+
+    fn insert_run(&mut self, start: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        let bucket = Self::bucket(len);
+        let head = self.heads[bucket];
+        let len = u32::try_from(len).unwrap();
+        unsafe {
+            *self.run(start) = FreeRun {
+                len,
+                next: head,
+                prev: NO_RUN,
+            };
+            (*self.run(start + len as usize - 1)).len = len;
+            if head != NO_RUN {
+                (*self.run(head as usize)).prev = u32::try_from(start).unwrap();
+            }
+        }
+        self.heads[bucket] = u32::try_from(start).unwrap();
+    }
+
+    /// Unlink the free run starting at `start`, returning its length
+    fn remove_run(&mut self, start: usize) -> usize {
+        let FreeRun { len, next, prev } = unsafe { *self.run(start) };
+        if prev == NO_RUN {
+            self.heads[Self::bucket(len as usize)] = next;
+        } else {
+            unsafe { (*self.run(prev as usize)).next = next };
+        }
+        if next != NO_RUN {
+            unsafe { (*self.run(next as usize)).prev = prev };
+        }
+        len as usize
+    }
+
+    /// First page of the free run holding the free page `page`
+    fn run_start(&self, page: usize) -> usize {
+        let used = self.used.as_ptr::<u64>();
+        let mut word = page / 64;
+        let mut bits = unsafe { *used.add(word) } & ((1 << (page % 64)) - 1);
+        while bits == 0 {
+            if word == 0 {
+                return 0;
+            }
+            word -= 1;
+            bits = unsafe { *used.add(word) };
+        }
+        word * 64 + 64 - bits.leading_zeros() as usize
+    }
+
+    /// A free run that holds `count` pages from a page index that is `phase`
+    /// modulo `step`, as the run's first page and that index. Only buckets
+    /// whose runs are long enough are searched.
+    fn find_free_run(&self, count: usize, step: usize, phase: usize) -> Option<(usize, usize)> {
+        (Self::bucket(count.max(1))..RUN_BUCKETS).find_map(|bucket| {
+            let mut run = self.heads[bucket];
+            while run != NO_RUN {
+                let FreeRun { len, next, .. } = unsafe { *self.run(run as usize) };
+                let start = Self::align_up(run as usize, step, phase);
+                if start + count <= run as usize + len as usize {
+                    return Some((run as usize, start));
+                }
+                run = next;
+            }
+            None
+        })
+    }
+
+    /// Take the `count` pages at `start` out of the free run beginning at `run`
+    fn claim(&mut self, run: usize, start: usize, count: usize) {
+        let len = self.remove_run(run);
+        self.insert_run(run, start - run);
+        self.insert_run(start + count, run + len - start - count);
+        self.freed -= count;
+        self.mark(start, count, true);
+    }
+
+    #[cfg(test)]
+    fn alloc(&mut self, count: usize) -> Option<usize> {
+        self.alloc_aligned(count, 1, 0)
+    }
+
+    /// Claim `count` free pages starting at a page index that is `phase`
+    /// modulo `step`
+    fn alloc_aligned(&mut self, count: usize, step: usize, phase: usize) -> Option<usize> {
+        if let Some((run, start)) = self.find_free_run(count, step, phase) {
+            self.claim(run, start, count);
+            return Some(start);
+        }
+
+        let start = Self::align_up(self.end, step, phase);
+        self.alloc_at(start, count).then_some(start)
+    }
+
+    /// Claim the `count` pages at `start` if none of them is in use
+    fn alloc_at(&mut self, start: usize, count: usize) -> bool {
+        if start + count > self.capacity || (start..start + count).any(|page| self.is_used(page)) {
+            return false;
+        }
+
+        if start < self.end {
+            // no run reaches `end`, so the pages lie within a single run
+            self.claim(self.run_start(start), start, count);
+            return true;
+        }
+        // pages skipped between the old end and `start` are free as well
+        self.insert_run(self.end, start - self.end);
+        self.freed += start - self.end;
+        self.end = start + count;
+        self.mark(start, count, true);
+        true
+    }
+
+    fn free(&mut self, start: usize, count: usize) {
+        self.mark(start, count, false);
+        self.freed += count;
+
+        // merge with the free runs on either side
+        let (mut first, mut last) = (start, start + count);
+        if first > 0 && !self.is_used(first - 1) {
+            first -= unsafe { (*self.run(first - 1)).len } as usize;
+            self.remove_run(first);
+        }
+        if last < self.end && !self.is_used(last) {
+            last += self.remove_run(last);
+        }
+
+        if last == self.end {
+            self.freed -= last - first;
+            self.end = first;
+        } else {
+            self.insert_run(first, last - first);
+        }
+    }
+}
+
+/// Maps every page of the arena to the `MiniHeap` owning it, stored as slot
+/// index + 1 so that zero means unowned
+struct PageTable {
+    owners: Mapping,
+    begin: usize,
+    page_count: usize,
+}
+
+impl PageTable {
+    fn init(begin: *mut (), page_count: usize) -> Self {
+        let size = page_count * core::mem::size_of::<u32>();
+        Self {
+            owners: Mapping::new(size),
+            begin: begin as usize,
+            page_count,
+        }
+    }
+
+    fn set(&mut self, begin: *const (), page_count: usize, value: u32) {
+        let Some(first) = (begin as usize)
+            .checked_sub(self.begin)
+            .map(|at| at / PAGE_SIZE)
+        else {
+            return;
+        };
+        let last = (first + page_count).min(self.page_count);
+        let owners = self.owners.as_ptr::<AtomicU32>();
+        (first..last)
+            .for_each(|page| unsafe { &*owners.add(page) }.store(value, Ordering::Release));
+    }
+}
+
+/// Read only view of the page table that resolves pointers to their
+/// `MiniHeap` without going through the arena, and so without the heap lock.
+/// Entries are only written under the lock.
+#[derive(Clone, Copy)]
+pub struct OwnerLookup {
+    owners: *const AtomicU32,
+    begin: usize,
+    page_count: usize,
+    mini_heap_slab: *mut MiniHeap,
+}
+
+unsafe impl Sync for OwnerLookup {}
+unsafe impl Send for OwnerLookup {}
+
+impl OwnerLookup {
+    /// The `MiniHeap` owning the page `ptr` points into, in constant time
+    ///# Safety
+    /// The arena the lookup came from must still be alive
+    pub unsafe fn get(&self, ptr: *const ()) -> Option<*mut MiniHeap> {
+        let page = (ptr as usize).checked_sub(self.begin)? / PAGE_SIZE;
+        if page >= self.page_count {
+            return None;
+        }
+        let pos = (*self.owners.add(page))
+            .load(Ordering::Acquire)
+            .checked_sub(1)?;
+        Some(self.mini_heap_slab.add(pos as usize))
+    }
+}
+
+/// Spans live in one contiguous virtual range that maps the whole span file,
+/// so a span's address is determined by its page offset inside the file. Only
+/// meshing points a virtual span at other pages of the file.
 pub struct MeshableArena {
     pub(crate) arena_begin: *mut (),
-    pub mini_heaps: DynArray<MiniHeap, NUM_BINS>,
+    pub mini_heaps: DynArray<MiniHeap, MAX_MINI_HEAPS>,
+    mini_heap_slab: Mapping,
+    mini_heap_count: usize,
+    /// descriptor of the span file, -1 for anonymous memory
+    pub(crate) fd: i32,
+    backing: Backing,
+    pages: PageMap,
+    owners: PageTable,
 }
 
 unsafe impl Sync for MeshableArena {}
 unsafe impl Send for MeshableArena {}
 
 impl MeshableArena {
+    /// An arena backed as configured in the environment
     pub fn init() -> Self {
-        // TODO: check if meshing enabled
+        Self::with_backing(Backing::from_env())
+    }
+
+    /// An arena whose spans are backed by `backing`. A backing that can't be
+    /// set up is reported and replaced by its fallback, down to anonymous
+    /// memory.
+    pub fn with_backing(mut backing: Backing) -> Self {
+        let slab_size = core::mem::size_of::<MiniHeap>() * MAX_MINI_HEAPS;
+        let (arena_begin, fd) = loop {
+            match Self::map_spans(backing) {
+                Ok(mapped) => break mapped,
+                Err(err) => match backing.fallback() {
+                    Some(fallback) => {
+                        report_fallback(backing, fallback, &err);
+                        backing = fallback;
+                    }
+                    None => break (null_mut(), -1),
+                },
+            }
+        };
+        // without address space for the spans every allocation fails
+        let page_count = if arena_begin.is_null() {
+            0
+        } else {
+            ARENA_SIZE / PAGE_SIZE
+        };
         Self {
-            arena_begin: null_mut(),
-            mini_heaps: DynArray::<MiniHeap, NUM_BINS>::create(),
+            arena_begin,
+            mini_heaps: DynArray::<MiniHeap, MAX_MINI_HEAPS>::create(),
+            mini_heap_slab: Mapping::new(slab_size),
+            mini_heap_count: 0,
+            fd,
+            backing,
+            pages: PageMap::init(page_count),
+            owners: PageTable::init(arena_begin, page_count),
         }
     }
 
+    /// Map the whole span range backed by `backing`, along with the descriptor
+    /// of its span file
+    fn map_spans(backing: Backing) -> Result<(*mut (), i32)> {
+        let Some(fd) = open_span_file(backing, ARENA_SIZE)? else {
+            return unsafe { mmap_anonymous(ARENA_SIZE) }.map(|begin| (begin.cast(), -1));
+        };
+        match unsafe { mmap(null_mut(), fd, ARENA_SIZE, 0) } {
+            Ok(begin) => Ok((begin.cast(), fd)),
+            Err(err) => {
+                let _ = unsafe { close(fd) };
+                Err(err)
+            }
+        }
+    }
+
+    /// Whether spans can be meshed, which needs them to be backed by a file
+    /// with base sized pages
+    pub const fn can_mesh(&self) -> bool {
+        self.backing.can_mesh()
+    }
+
+    /// Claim `page_count` pages of the arena at an address that is a multiple
+    /// of `align`, noting whether they are still zero
+    fn take_pages(&mut self, page_count: usize, align: usize) -> Option<Span> {
+        let step = (align / PAGE_SIZE).max(1);
+        let phase = (step - self.arena_begin as usize / PAGE_SIZE % step) % step;
+        let offset = self.pages.alloc_aligned(page_count, step, phase)?;
+
+        let mut span = Span::new(self.page_address(offset), offset, page_count);
+        span.zeroed = self.pages.is_clean(offset, page_count);
+        self.pages.mark_dirty(offset, page_count, true);
+        Some(span)
+    }
+
+    fn page_address(&self, offset: usize) -> *mut () {
+        unsafe { self.arena_begin.cast::<Page>().add(offset) }.cast()
+    }
+
+    fn page_offset(&self, ptr: *const ()) -> usize {
+        (ptr as usize - self.arena_begin as usize) / PAGE_SIZE
+    }
+
+    /// Hand out a fresh span of `page_count` pages
+    pub fn alloc_span(&mut self, page_count: usize) -> Option<Span> {
+        self.take_pages(page_count, PAGE_SIZE)
+    }
+
+    /// Hand out a fresh span whose address is a multiple of `align`
+    pub fn alloc_aligned_span(&mut self, page_count: usize, align: usize) -> Option<Span> {
+        self.take_pages(page_count, align)
+    }
+
+    /// Extend the span of `page_count` pages at page `offset` to
+    /// `new_page_count` pages by claiming the pages right behind it. Returns
+    /// `false` if any of them is taken.
+    pub fn grow_span(&mut self, offset: usize, page_count: usize, new_page_count: usize) -> bool {
+        let added = new_page_count - page_count;
+        if !self.pages.alloc_at(offset + page_count, added) {
+            return false;
+        }
+        self.pages.mark_dirty(offset + page_count, added, true);
+        true
+    }
+
+    /// Hand the pages of a span past `new_page_count` back to the arena
+    pub fn shrink_span(&mut self, offset: usize, page_count: usize, new_page_count: usize) {
+        self.free_pages(offset + new_page_count, page_count - new_page_count);
+    }
+
+    /// Number of span file pages currently handed out
+    pub const fn pages_in_use(&self) -> usize {
+        self.pages.end - self.pages.freed
+    }
+
+    /// Hand the pages of the span file at `offset` back to the arena, after
+    /// discarding their contents
+    pub fn free_pages(&mut self, offset: usize, page_count: usize) {
+        self.discard_pages(offset, page_count);
+        self.pages.free(offset, page_count);
+    }
+
+    /// Return the memory behind the pages at `offset` to the kernel by punching
+    /// them out of the span file, so that they read as zero afterwards. The
+    /// pages themselves stay claimed. Hugetlbfs only punches out whole huge
+    /// pages, so its pages are left alone.
+    pub fn discard_pages(&mut self, offset: usize, page_count: usize) {
+        let discarded = match self.backing {
+            Backing::HugeTlbFs => return,
+            Backing::Anonymous => unsafe {
+                madvise(self.page_address(offset).cast(), page_count * PAGE_SIZE)
+            },
+            Backing::Memfd | Backing::Tmpfs => unsafe {
+                fallocate(self.fd, offset * PAGE_SIZE, page_count * PAGE_SIZE)
+            },
+        };
+        if discarded.is_ok() {
+            self.pages.mark_dirty(offset, page_count, false);
+        }
+    }
+
+    /// Point the virtual span at `begin` to the span file pages at `offset`
     ///# Safety
-    /// Unsafe
+    /// `begin` must be a span of `page_count` pages owned by the arena
+    pub unsafe fn remap(&self, begin: *mut (), offset: usize, page_count: usize) -> Result<()> {
+        mmap(
+            begin.cast(),
+            self.fd,
+            page_count * PAGE_SIZE,
+            offset * PAGE_SIZE,
+        )
+        .map(|_| ())
+    }
+
+    /// Move the spans onto a fresh copy of the span file, so that a forked
+    /// child stops sharing their pages with its parent. Only the pages of live
+    /// `MiniHeap`s are copied, and meshed spans are pointed at their new pages
+    /// again. Anonymous arenas are already copied on write by the fork.
+    ///# Errors
+    /// Fails if the copy can't be mapped, leaving the arena on the old file
+    pub fn reopen_span_file(&mut self) -> Result<()> {
+        let Some(fd) = open_span_file(self.backing, ARENA_SIZE)? else {
+            return Ok(());
+        };
+
+        let mapped = unsafe { mmap(null_mut(), fd, ARENA_SIZE, 0) }.and_then(|copy| {
+            let copy = copy.cast::<Page>();
+            for mh in self.iter_mini_heaps() {
+                let heap = unsafe { mh.as_ref().unwrap() };
+                let begin = self.page_address(heap.span_offset).cast::<Page>();
+                unsafe {
+                    copy.add(heap.span_offset)
+                        .copy_from_nonoverlapping(begin, heap.span_pages);
+                }
+            }
+            let _ = unsafe { munmap(copy.cast(), ARENA_SIZE) };
+            unsafe { mmap(self.arena_begin.cast(), fd, ARENA_SIZE, 0) }
+        });
+        if let Err(err) = mapped {
+            let _ = unsafe { close(fd) };
+            return Err(err);
+        }
+
+        let _ = unsafe { close(core::mem::replace(&mut self.fd, fd)) };
+        for mh in self.iter_mini_heaps() {
+            let heap = unsafe { mh.as_ref().unwrap() };
+            for begin in heap.spans().skip(1) {
+                unsafe { self.remap(begin.cast(), heap.span_offset, heap.span_pages) }?;
+            }
+        }
+        Ok(())
+    }
+
+    ///# Safety
+    /// `span` must have been handed out by `alloc_span`
     ///
     #[allow(clippy::redundant_closure_for_method_calls)]
-    pub unsafe fn generate_mini_heap(&mut self, alloc: *mut (), bytes: usize) -> *mut MiniHeap {
+    pub unsafe fn generate_mini_heap(&mut self, span: Span, object_size: usize) -> *mut MiniHeap {
         let mini_heaps = self.mini_heaps.as_mut_slice().as_mut().unwrap();
 
-        let empty = mini_heaps.iter().position(|x| x.is_none());
-        let size = core::mem::size_of::<*mut MiniHeap>();
-        let new_heap = unsafe { OneWayMmapHeap.malloc(size) as *mut MiniHeap };
-        new_heap.write(MiniHeap::new(alloc, bytes));
-
-        match empty {
+        match mini_heaps.iter().position(|x| x.is_none()) {
             Some(pos) => {
+                // metadata slots are recycled together with their registry entry
+                let new_heap = self.mini_heap_slab.as_ptr::<MiniHeap>().add(pos);
+                new_heap.write(MiniHeap::new(span, object_size));
                 mini_heaps[pos] = Some(new_heap);
-                self.mini_heaps.inner().add(pos).cast()
+                self.mini_heap_count += 1;
+                self.track_span(span.begin, span.length, Some(new_heap));
+                new_heap
             }
 
-            None => {
-                todo!()
+            None => null_mut(),
+        }
+    }
+
+    ///# Safety
+    /// `mh` must have been produced by this arena and must not be used afterwards
+    pub unsafe fn release_mini_heap(&mut self, mh: *mut MiniHeap) {
+        let heap = mh.as_ref().unwrap();
+        for begin in heap.spans() {
+            let offset = self.page_offset(begin.cast());
+            self.track_span(begin.cast(), heap.span_pages, None);
+            // spans meshed into `heap` go back to mapping their own pages. One
+            // that can't still aliases the pages of `heap`, so its own pages
+            // stay claimed to keep it from being handed out again.
+            if offset == heap.span_offset
+                || self.remap(begin.cast(), offset, heap.span_pages).is_ok()
+            {
+                self.free_pages(offset, heap.span_pages);
             }
         }
+        self.forget_mini_heap(mh);
     }
+
+    /// Drop `mh` from the registry without touching its spans
+    ///# Safety
+    /// `mh` must have been produced by this arena and must not be used afterwards
+    pub unsafe fn forget_mini_heap(&mut self, mh: *mut MiniHeap) {
+        let mini_heaps = self.mini_heaps.as_mut_slice().as_mut().unwrap();
+        let pos = usize::try_from(mh.offset_from(self.mini_heap_slab.as_ptr())).unwrap();
+        if mini_heaps[pos] == Some(mh) {
+            mini_heaps[pos] = None;
+            self.mini_heap_count -= 1;
+        }
+    }
+
+    /// Record `mh` as the owner of the `page_count` pages at `begin`, or clear
+    /// their owner if `mh` is `None`. Lookups through `get_mini_heap` only see
+    /// tracked pages.
+    pub fn track_span(&mut self, begin: *mut (), page_count: usize, mh: Option<*mut MiniHeap>) {
+        let value = mh.map_or(0, |mh| {
+            let pos = unsafe { mh.offset_from(self.mini_heap_slab.as_ptr()) };
+            u32::try_from(pos + 1).unwrap()
+        });
+        self.owners.set(begin, page_count, value);
+    }
+
+    /// Number of live `MiniHeap`s, those that `iter_mini_heaps` yields
+    pub const fn mini_heap_count(&self) -> usize {
+        self.mini_heap_count
+    }
+
+    /// Iterate over every live `MiniHeap` owned by the arena
+    pub fn iter_mini_heaps(&self) -> impl Iterator<Item = *mut MiniHeap> + '_ {
+        let mini_heaps = unsafe { self.mini_heaps.as_slice().as_ref().unwrap() };
+        mini_heaps.iter().filter_map(|x| *x)
+    }
+
+    /// The `MiniHeap` owning the page `ptr` points into, in constant time
     ///# Safety
     /// Unsafe
     pub unsafe fn get_mini_heap(&self, ptr: *mut ()) -> Option<*mut MiniHeap> {
-        let mini_heaps = self.mini_heaps.as_slice();
-
-        mini_heaps
-            .as_ref()
-            .unwrap()
-            .iter()
-            .find(|x| {
-                if let Some(mh) = x {
-                    mh.as_ref().unwrap().arena_begin == ptr.cast()
-                } else {
-                    false
-                }
-            })
-            .map(|x| x as *const _ as *mut MiniHeap)
+        self.owner_lookup().get(ptr)
+    }
+
+    /// A view of the page owners that can be read without borrowing the arena
+    pub const fn owner_lookup(&self) -> OwnerLookup {
+        OwnerLookup {
+            owners: self.owners.owners.as_ptr(),
+            begin: self.owners.begin,
+            page_count: self.owners.page_count,
+            mini_heap_slab: self.mini_heap_slab.as_ptr(),
+        }
+    }
+}
+
+impl Drop for MeshableArena {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.arena_begin.is_null() {
+                let _ = munmap(self.arena_begin.cast(), ARENA_SIZE);
+            }
+            if self.fd >= 0 {
+                let _ = close(self.fd);
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::arena_fs::HUGETLBFS_DIR;
 
     #[test]
     fn test_generate_mini_heap() {
         let mut arena = MeshableArena::init();
-        unsafe { arena.generate_mini_heap(null_mut(), 0) };
-        unsafe { arena.generate_mini_heap(null_mut(), 0) };
+        unsafe { arena.generate_mini_heap(Span::new(null_mut(), 0, 1), 0) };
+        unsafe { arena.generate_mini_heap(Span::new(null_mut(), 0, 1), 0) };
+    }
+
+    #[test]
+    fn get_mini_heap_resolves_interior_pointers() {
+        let mut arena = MeshableArena::init();
+        let span = arena.alloc_span(1).unwrap();
+        let mh = unsafe { arena.generate_mini_heap(span, 64) };
+        let interior = unsafe { span.begin.cast::<u8>().add(64 * 3 + 5) }.cast();
+
+        assert_eq!(unsafe { arena.get_mini_heap(interior) }, Some(mh));
+
+        let large = arena.alloc_span(3).unwrap();
+        let large_mh = unsafe { arena.generate_mini_heap(large, large.byte_length()) };
+        let last = unsafe { large.begin.cast::<u8>().add(large.byte_length() - 1) };
+        assert_eq!(unsafe { arena.get_mini_heap(last.cast()) }, Some(large_mh));
+
+        unsafe { arena.release_mini_heap(mh) };
+        assert_eq!(unsafe { arena.get_mini_heap(interior) }, None);
+        assert_eq!(unsafe { arena.get_mini_heap(null_mut()) }, None);
+        assert_eq!(unsafe { arena.get_mini_heap(usize::MAX as *mut ()) }, None);
+    }
+
+    #[test]
+    fn freed_pages_are_reused() {
+        let mut pages = PageMap::init(1024);
+        assert_eq!(pages.alloc(2), Some(0));
+        assert_eq!(pages.alloc(3), Some(2));
+        assert_eq!(pages.alloc(1), Some(5));
+
+        pages.free(2, 3);
+        assert_eq!(pages.alloc(4), Some(6));
+        assert_eq!(pages.alloc(2), Some(2));
+        assert_eq!(pages.alloc(2000), None);
+
+        assert!(pages.alloc_at(4, 1));
+        assert!(!pages.alloc_at(4, 1));
+        assert!(pages.alloc_at(12, 2));
+        assert_eq!(pages.alloc(2), Some(10));
+
+        let mut pages = PageMap::init(1024);
+        assert_eq!(pages.alloc_aligned(2, 8, 3), Some(3));
+        assert_eq!(pages.alloc(3), Some(0));
+        assert_eq!(pages.alloc(1), Some(5));
+        assert_eq!(pages.alloc_aligned(1, 8, 3), Some(11));
+        assert_eq!(pages.alloc(4), Some(6));
+    }
+
+    #[test]
+    fn free_runs_coalesce() {
+        let mut pages = PageMap::init(1024);
+        (0..4).for_each(|k| assert_eq!(pages.alloc(4), Some(4 * k)));
+        pages.free(4, 4);
+        pages.free(12, 4);
+        assert_eq!((pages.end, pages.freed), (12, 4));
+
+        // the run left of the freed pages is extended, and found by its length
+        pages.free(0, 4);
+        assert_eq!(pages.alloc(8), Some(0));
+        pages.free(2, 4);
+        assert!(pages.alloc_at(3, 2));
+        assert_eq!(pages.alloc(1), Some(5));
+        assert_eq!(pages.alloc(1), Some(2));
+
+        (2..6).for_each(|page| pages.free(page, 1));
+        pages.free(0, 2);
+        pages.free(6, 6);
+        assert_eq!((pages.end, pages.freed), (0, 0));
+    }
+
+    #[test]
+    fn spans_are_carved_out_of_one_mapping() {
+        let mut arena = MeshableArena::init();
+        let first = arena.alloc_span(3).unwrap();
+        assert_eq!(first.begin, arena.arena_begin);
+        let aligned = arena.alloc_aligned_span(2, 16 * PAGE_SIZE).unwrap();
+        assert_eq!(aligned.begin as usize % (16 * PAGE_SIZE), 0);
+        assert_eq!(aligned.begin, arena.page_address(aligned.offset));
+        unsafe { aligned.begin.cast::<u64>().write(7) };
+
+        // spans only grow into pages nobody else holds
+        assert!(arena.grow_span(aligned.offset, 2, 4));
+        assert!(!arena.grow_span(first.offset, 3, aligned.offset + 1));
+        arena.shrink_span(aligned.offset, 4, 1);
+        assert_eq!(arena.pages_in_use(), 3 + 1);
+    }
+
+    #[test]
+    fn unusable_backings_fall_back_to_the_next_one() {
+        let mut arena = MeshableArena::with_backing(Backing::HugeTlbFs);
+        if std::path::Path::new(HUGETLBFS_DIR).exists() {
+            return;
+        }
+        assert_eq!(arena.backing, Backing::Memfd);
+        assert!(arena.fd >= 0 && arena.can_mesh());
+        let span = arena.alloc_span(1).unwrap();
+        unsafe { span.begin.cast::<u64>().write(7) };
+    }
+
+    #[test]
+    fn anonymous_arenas_hand_out_spans_but_never_mesh() {
+        let mut arena = MeshableArena::with_backing(Backing::Anonymous);
+        assert!(!arena.can_mesh());
+        assert_eq!(arena.fd, -1);
+        let span = arena.alloc_span(2).unwrap();
+        unsafe { span.begin.cast::<u8>().write_bytes(1, span.byte_length()) };
+        let mh = unsafe { arena.generate_mini_heap(span, 256) };
+        unsafe { arena.release_mini_heap(mh) };
+        assert!(arena.alloc_span(2).unwrap().zeroed);
+        assert_eq!(unsafe { *span.begin.cast::<u8>() }, 0);
+
+        assert!(MeshableArena::with_backing(Backing::Tmpfs).can_mesh());
+    }
+
+    #[test]
+    fn released_spans_are_punched_out_and_come_back_zeroed() {
+        let mut arena = MeshableArena::with_backing(Backing::Memfd);
+        let span = arena.alloc_span(2).unwrap();
+        assert!(span.zeroed);
+        let mh = unsafe { arena.generate_mini_heap(span, 1024) };
+        let heap = unsafe { mh.as_ref().unwrap() };
+        let ptr = heap.malloc_at(0).unwrap().cast::<u8>();
+        unsafe { ptr.write_bytes(0xff, span.byte_length()) };
+        heap.free_offset(0);
+        assert!(!heap.is_zeroed(0));
+        assert!(heap.is_zeroed(1));
+
+        let mut resident = [0u8; 2];
+        let is_resident = |resident: &mut [u8; 2]| unsafe {
+            libc::mincore(span.begin.cast(), span.byte_length(), resident.as_mut_ptr());
+            resident.iter().any(|page| page & 1 != 0)
+        };
+        assert!(is_resident(&mut resident));
+        unsafe { arena.release_mini_heap(mh) };
+        assert!(!is_resident(&mut resident));
+
+        let reused = arena.alloc_span(2).unwrap();
+        assert_eq!(reused.begin, span.begin);
+        assert!(reused.zeroed);
+        assert_eq!(unsafe { *ptr }, 0);
+    }
+
+    #[test]
+    fn reopened_span_files_keep_contents_and_meshes() {
+        let mut arena = MeshableArena::init();
+        let (dst, src) = (arena.alloc_span(1).unwrap(), arena.alloc_span(1).unwrap());
+        let mh = unsafe { arena.generate_mini_heap(dst, 64) };
+        unsafe {
+            dst.begin.cast::<u64>().write(7);
+            arena.remap(src.begin, dst.offset, 1).unwrap();
+            let heap = mh.as_mut().unwrap();
+            heap.meshed_spans[0] = src.begin.cast();
+            heap.mesh_count = 1;
+        }
+        let old_fd = arena.fd;
+        let old = unsafe { mmap(null_mut(), old_fd, PAGE_SIZE, dst.byte_offset()) }.unwrap();
+
+        arena.reopen_span_file().unwrap();
+        assert_ne!(arena.fd, old_fd);
+        assert_eq!(unsafe { src.begin.cast::<u64>().read() }, 7);
+        unsafe { dst.begin.cast::<u64>().write(9) };
+        assert_eq!(unsafe { src.begin.cast::<u64>().read() }, 9);
+        // the old file is left alone
+        assert_eq!(unsafe { old.cast::<u64>().read() }, 7);
+        unsafe { munmap(old, PAGE_SIZE) }.unwrap();
+    }
+
+    #[test]
+    fn dropping_the_arena_unmaps_its_metadata() {
+        let is_mapped =
+            |ptr: *mut u8| unsafe { libc::msync(ptr.cast(), PAGE_SIZE, libc::MS_ASYNC) == 0 };
+
+        let mut arena = MeshableArena::init();
+        let span = arena.alloc_span(1).unwrap();
+        unsafe { arena.generate_mini_heap(span, 64) };
+        let metadata = [
+            arena.pages.used.as_ptr(),
+            arena.pages.dirty.as_ptr(),
+            arena.pages.runs.as_ptr(),
+            arena.owners.owners.as_ptr(),
+            arena.mini_heap_slab.as_ptr(),
+            arena.mini_heaps.inner().cast(),
+        ];
+        assert!(metadata.iter().all(|ptr| is_mapped(*ptr)));
+        drop(arena);
+        assert!(!metadata.iter().any(|ptr| is_mapped(*ptr)));
     }
 }
@@ -1,35 +1,177 @@
-use core::{ptr::null_mut, sync::atomic::AtomicU64};
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-use crate::{comparatomic::Comparatomic, meshable_arena::Page};
+use crate::{
+    bitmap::Bitmap,
+    comparatomic::Comparatomic,
+    global_heap::Meshable,
+    meshable_arena::{Page, Span},
+    MAX_MESHES, MAX_OBJECTS_PER_MINI_HEAP, PAGE_SIZE,
+};
 
 pub struct MiniHeap {
     pub arena_begin: *mut Page,
+    /// page offset of the span inside the span file
+    pub span_offset: usize,
     pub object_size: usize,
-    pub span_start: *mut Self,
-    pub current: Comparatomic<AtomicU64>,
+    pub span_pages: usize,
+    pub object_count: usize,
+    pub bitmap: Bitmap,
+    /// slots that may hold stale data, all others are known to be zero
+    pub dirty: Bitmap,
+    /// id of the shuffle vector the heap is attached to, zero while detached
+    pub owner: Comparatomic<AtomicUsize>,
+    /// spans of other heaps that have been meshed into this one and now alias
+    /// its physical pages
+    pub meshed_spans: [*mut Page; MAX_MESHES - 1],
+    pub mesh_count: usize,
 }
 
 impl MiniHeap {
-    pub unsafe fn new(start: *mut (), object_size: usize) -> Self {
+    ///# Safety
+    /// `span` must stay mapped for as long as the `MiniHeap` is alive
+    pub unsafe fn new(span: Span, object_size: usize) -> Self {
+        let object_count = (span.length * PAGE_SIZE)
+            .checked_div(object_size)
+            .map_or(0, |count| count.min(MAX_OBJECTS_PER_MINI_HEAP));
+        let dirty = Bitmap::new();
+        if !span.zeroed {
+            dirty.fill(object_count);
+        }
+
         MiniHeap {
-            arena_begin: start.cast(),
+            arena_begin: span.begin.cast(),
+            span_offset: span.offset,
             object_size,
-            span_start: null_mut(),
-            current: Comparatomic::new(0),
+            span_pages: span.length,
+            object_count,
+            bitmap: Bitmap::new(),
+            dirty,
+            owner: Comparatomic::new(0),
+            meshed_spans: [null_mut(); MAX_MESHES - 1],
+            mesh_count: 0,
+        }
+    }
+
+    /// Whether the heap is attached to a shuffle vector, in which case only the
+    /// thread owning that vector may hand out its free slots
+    pub fn is_attached(&self) -> bool {
+        self.owner.load(Ordering::Acquire) != 0
+    }
+
+    /// Whether the heap holds a single object covering its whole span. Spans of
+    /// size classes always hold at least `MIN_OBJECTS_PER_SPAN` objects.
+    pub const fn is_large_alloc(&self) -> bool {
+        self.object_count == 1
+    }
+
+    pub const fn span_size(&self) -> usize {
+        self.span_pages * PAGE_SIZE
+    }
+
+    /// Every virtual span backed by this heap's pages, starting with its own
+    pub fn spans(&self) -> impl Iterator<Item = *mut Page> + Clone + '_ {
+        core::iter::once(self.arena_begin)
+            .chain(self.meshed_spans[..self.mesh_count].iter().copied())
+    }
+
+    fn span_containing(&self, ptr: *const ()) -> Option<*mut Page> {
+        let size = self.span_size();
+        self.spans()
+            .find(|begin| (*begin as usize..*begin as usize + size).contains(&(ptr as usize)))
+    }
+
+    pub fn contains(&self, ptr: *const ()) -> bool {
+        self.span_containing(ptr).is_some()
+    }
+
+    pub fn offset_for(&self, ptr: *const ()) -> usize {
+        let begin = self.span_containing(ptr);
+        debug_assert!(begin.is_some(), "pointer does not belong to this span");
+        (ptr as usize - begin.unwrap_or(self.arena_begin) as usize) / self.object_size
+    }
+
+    /// Whether the spans of `self` and `other` can be merged onto the same pages
+    pub fn is_meshable_with(&self, other: &Self) -> bool {
+        !self.is_large_alloc()
+            && self.object_size == other.object_size
+            && self.span_pages == other.span_pages
+            && self.mesh_count + other.mesh_count + 2 <= MAX_MESHES
+            && self.bitmap.bits().is_meshable(other.bitmap.bits())
+    }
+
+    pub fn ptr_for(&self, offset: usize) -> *mut () {
+        debug_assert!(offset < self.object_count);
+        unsafe {
+            self.arena_begin
+                .cast::<u8>()
+                .add(offset * self.object_size)
+                .cast()
         }
     }
+
+    /// Claim the slot at `offset`, returning its address if it was free
+    pub fn malloc_at(&self, offset: usize) -> Option<*mut ()> {
+        (offset < self.object_count && self.bitmap.try_set(offset)).then(|| self.ptr_for(offset))
+    }
+
+    /// Claim the first free slot in the span
+    pub fn malloc(&self) -> *mut () {
+        self.bitmap
+            .free_indices(self.object_count)
+            .find_map(|offset| self.malloc_at(offset))
+            .unwrap_or(null_mut())
+    }
+
+    pub fn free_offset(&self, offset: usize) {
+        self.mark_dirty(offset);
+        let was_set = self.bitmap.unset(offset);
+        debug_assert!(was_set, "double free of offset {offset}");
+    }
+
+    /// Record that the slot at `offset` has been handed back by its user and
+    /// can no longer be assumed to be zero
+    pub fn mark_dirty(&self, offset: usize) {
+        self.dirty.try_set(offset);
+    }
+
+    /// Whether the slot at `offset` is still zero from when its span was mapped
+    pub fn is_zeroed(&self, offset: usize) -> bool {
+        !self.dirty.is_set(offset)
+    }
+
+    pub fn in_use_count(&self) -> usize {
+        self.bitmap.in_use_count()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.in_use_count() == self.object_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.in_use_count() == 0
+    }
 }
 
 impl core::fmt::Debug for MiniHeap {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> Result<(), core::fmt::Error> {
-        write!(f, "Miniheap debuggaa {:?}", self.arena_begin)
+        write!(
+            f,
+            "MiniHeap {:?} ({} x {}b, {} in use)",
+            self.arena_begin,
+            self.object_count,
+            self.object_size,
+            self.in_use_count()
+        )
     }
 }
 impl PartialEq for MiniHeap {
     fn eq(&self, other: &Self) -> bool {
-        self.object_size == other.object_size
-            && self.span_start == other.span_start
-            && self.current == other.current
+        self.arena_begin == other.arena_begin
+            && self.object_size == other.object_size
+            && self.span_pages == other.span_pages
     }
 }
 
@@ -42,10 +184,32 @@ impl Drop for MiniHeap {
 #[cfg(test)]
 mod tests {
     use super::MiniHeap;
+    use crate::{meshable_arena::Span, one_way_mmap_heap::OneWayMmapHeap, PAGE_SIZE};
 
     #[test]
     pub fn test_dyn_array_of_mini_heaps() {
         let mut h = crate::fake_std::dynarray::DynArray::<MiniHeap, 32>::create();
         let _slice = h.as_mut_slice();
     }
+
+    #[test]
+    fn malloc_at_and_free_offset_track_slots() {
+        let span = unsafe { OneWayMmapHeap.malloc(PAGE_SIZE) };
+        let mh = unsafe { MiniHeap::new(Span::new(span, 0, 1), 1024) };
+        assert_eq!(mh.object_count, 4);
+        assert!(mh.is_empty());
+
+        let ptr = mh.malloc_at(2).unwrap();
+        assert_eq!(ptr as usize, span as usize + 2048);
+        assert!(mh.malloc_at(2).is_none());
+        assert_eq!(mh.offset_for(ptr), 2);
+
+        (0..3).for_each(|_| assert!(!mh.malloc().is_null()));
+        assert!(mh.is_full());
+        assert!(mh.malloc().is_null());
+
+        mh.free_offset(2);
+        assert_eq!(mh.in_use_count(), 3);
+        assert!(!mh.is_full());
+    }
 }
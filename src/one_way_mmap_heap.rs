@@ -1,4 +1,4 @@
-use crate::PAGE_SIZE;
+use crate::{utils::munmap, PAGE_SIZE};
 use core::ptr::null_mut;
 use libc::{mmap, MAP_ANONYMOUS, MAP_FAILED, MAP_NORESERVE, MAP_PRIVATE, PROT_READ, PROT_WRITE};
 
@@ -25,3 +25,35 @@ impl OneWayMmapHeap {
         self.map(size, MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE, -1)
     }
 }
+
+/// Zeroed memory that remembers its size and is unmapped on drop, for
+/// metadata that lives and dies with its owner
+pub struct Mapping {
+    ptr: *mut (),
+    size: usize,
+}
+
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    pub fn new(size: usize) -> Self {
+        let size = (size + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        Self {
+            ptr: unsafe { OneWayMmapHeap.malloc(size) },
+            size,
+        }
+    }
+
+    pub const fn as_ptr<T>(&self) -> *mut T {
+        self.ptr.cast()
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            let _ = unsafe { munmap(self.ptr.cast(), self.size) };
+        }
+    }
+}
@@ -1,23 +1,185 @@
 use rand_xoshiro::rand_core::{RngCore, SeedableRng};
 use rand_xoshiro::Xoshiro256PlusPlus;
+use spin::Mutex;
+
+use crate::utils::{auxv_random, get_pid, getenv, getrandom};
+
+// every generator gets a stream split off this one, each a jump of 2^128
+// steps ahead of the previous
+static STREAMS: Mutex<Option<Xoshiro256PlusPlus>> = Mutex::new(None);
 
 pub struct Rng {
     rng: Xoshiro256PlusPlus,
 }
 
 impl Rng {
+    /// A generator with a stream of its own. Streams come from a process wide
+    /// generator seeded from the kernel's entropy, or from `MESSLOC_SEED` for
+    /// reproducible runs.
     pub fn init() -> Self {
+        let mut streams = STREAMS.lock();
+        let base = streams.get_or_insert_with(|| {
+            getenv(b"MESSLOC_SEED\0")
+                .and_then(|seed| seed.parse().ok())
+                .map_or_else(seed_from_entropy, Xoshiro256PlusPlus::seed_from_u64)
+        });
+        base.jump();
+        Self { rng: base.clone() }
+    }
+
+    /// A generator with a fixed seed that doesn't draw from the shared streams
+    pub fn with_seed(seed: u64) -> Self {
         Self {
-            rng: Xoshiro256PlusPlus::seed_from_u64(0),
+            rng: Xoshiro256PlusPlus::seed_from_u64(seed),
         }
     }
 
+    /// A uniformly distributed value in `start..=end`, using Lemire's nearly
+    /// divisionless method: the high half of a widening multiply picks the
+    /// value, and the few draws that would bias it are rejected
+    #[allow(clippy::cast_possible_truncation)]
     pub fn in_range(&mut self, start: usize, end: usize) -> usize {
-        let range = 1 + end - start;
-        (usize::try_from(self.next()).unwrap() * range) >> 32
+        debug_assert!(start <= end);
+        let Some(range) = u64::try_from(end - start).unwrap().checked_add(1) else {
+            return start.wrapping_add(self.next() as usize);
+        };
+
+        let mut product = u128::from(self.next()) * u128::from(range);
+        // only draws whose low half falls below `2^64 % range` are biased
+        if (product as u64) < range {
+            let threshold = range.wrapping_neg() % range;
+            while (product as u64) < threshold {
+                product = u128::from(self.next()) * u128::from(range);
+            }
+        }
+        start + usize::try_from(product >> 64).unwrap()
     }
 
     pub fn next(&mut self) -> u64 {
         self.rng.next_u64()
     }
 }
+
+/// Seed from `getrandom`, falling back to the auxiliary vector's bytes mixed
+/// with the pid while the entropy pool isn't ready
+fn seed_from_entropy() -> Xoshiro256PlusPlus {
+    let mut seed = [0; 32];
+    if getrandom(&mut seed).is_err() {
+        seed[..16].copy_from_slice(&auxv_random().unwrap_or_default());
+        seed[16..20].copy_from_slice(&get_pid().to_ne_bytes());
+    }
+    Xoshiro256PlusPlus::from_seed(seed)
+}
+
+/// Keep new generators from being handed out until `unlock_streams`, so that
+/// a fork doesn't leave the streams locked in the child
+pub fn lock_streams() {
+    core::mem::forget(STREAMS.lock());
+}
+
+/// Hand out generators again. With `reseed`, as in a forked child, streams
+/// are split off a freshly seeded generator instead of repeating the parent's.
+///# Safety
+/// Must follow a call to `lock_streams`
+pub unsafe fn unlock_streams(reseed: bool) {
+    STREAMS.force_unlock();
+    if reseed {
+        *STREAMS.lock() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_SHUFFLE_VECTOR_LENGTH as N;
+
+    /// Pearson's chi-squared statistic of `counts` against a uniform spread
+    #[allow(clippy::cast_precision_loss)]
+    fn chi_squared(counts: &[usize]) -> f64 {
+        let expected = counts.iter().sum::<usize>() as f64 / counts.len() as f64;
+        counts
+            .iter()
+            .map(|count| (*count as f64 - expected).powi(2) / expected)
+            .sum()
+    }
+
+    /// Far above what a uniform distribution produces, about six standard
+    /// deviations past the mean of the chi-squared distribution
+    #[allow(clippy::cast_precision_loss)]
+    fn chi_squared_bound(buckets: usize) -> f64 {
+        let freedom = (buckets - 1) as f64;
+        freedom + 6.0 * (2.0 * freedom).sqrt()
+    }
+
+    #[test]
+    fn generators_get_independent_streams() {
+        let (mut first, mut second) = (Rng::init(), Rng::init());
+        let first: [u64; 4] = core::array::from_fn(|_| first.next());
+        let second: [u64; 4] = core::array::from_fn(|_| second.next());
+        assert_ne!(first, second);
+
+        let fresh = || seed_from_entropy().next_u64();
+        assert_ne!(fresh(), fresh());
+    }
+
+    #[test]
+    fn fixed_seeds_are_reproducible() {
+        let (mut first, mut second) = (Rng::with_seed(42), Rng::with_seed(42));
+        assert!((0..64).all(|_| first.next() == second.next()));
+        assert_ne!(Rng::with_seed(42).next(), Rng::with_seed(43).next());
+    }
+
+    #[test]
+    fn in_range_stays_within_bounds() {
+        for seed in 0..16 {
+            let mut rng = Rng::with_seed(seed);
+            for start in 0..N {
+                for end in start..N {
+                    assert!((start..=end).contains(&rng.in_range(start, end)));
+                }
+            }
+            assert_eq!(rng.in_range(5, 5), 5);
+            assert!(rng.in_range(usize::MAX - 1, usize::MAX) >= usize::MAX - 1);
+            rng.in_range(0, usize::MAX);
+        }
+    }
+
+    #[test]
+    fn in_range_is_uniform_over_shuffle_ranges() {
+        let mut rng = Rng::with_seed(7);
+        // `shuffle` draws from `start..=k`, `push` from `offset..=N - 1`
+        let ranges = (1..N)
+            .map(|k| (0, k))
+            .chain((0..N - 1).map(|offset| (offset, N - 1)));
+        for (start, end) in ranges {
+            let mut counts = [0; N];
+            let buckets = end - start + 1;
+            for _ in 0..buckets * 256 {
+                counts[rng.in_range(start, end) - start] += 1;
+            }
+            let statistic = chi_squared(&counts[..buckets]);
+            assert!(
+                statistic < chi_squared_bound(buckets),
+                "{start}..={end}: {statistic}"
+            );
+        }
+    }
+
+    #[test]
+    fn fisher_yates_shuffles_hit_every_permutation_evenly() {
+        let mut rng = Rng::with_seed(11);
+        // permutations of 4 entries, indexed by their digits in base 4
+        let mut counts = [0; 256];
+        for _ in 0..24 * 1000 {
+            let mut entries = [0, 1, 2, 3];
+            (1..entries.len()).rev().for_each(|k| {
+                entries.swap(k, rng.in_range(0, k));
+            });
+            counts[entries.iter().fold(0, |index, entry| index * 4 + entry)] += 1;
+        }
+
+        let seen: Vec<usize> = counts.into_iter().filter(|count| *count > 0).collect();
+        assert_eq!(seen.len(), 24);
+        assert!(chi_squared(&seen) < chi_squared_bound(seen.len()));
+    }
+}
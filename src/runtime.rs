@@ -1,50 +1,693 @@
-use core::alloc::Layout;
+use arrayvec::ArrayString;
+use core::{
+    alloc::Layout, ffi::c_void, fmt::Write, mem::size_of, sync::atomic::Ordering, time::Duration,
+};
 use spin::Mutex;
 
-use crate::global_heap::GlobalHeap;
+use crate::{
+    fork,
+    global_heap::{GlobalHeap, SizeMap},
+    meshable_arena::OwnerLookup,
+    mini_heap::MiniHeap,
+    stats::HeapStats,
+    thread_heap::ThreadHeap,
+    utils::{
+        create_signal_mask, new_signal_fd, pthread_create, read, sig_proc_mask, signalfd_siginfo,
+    },
+    MAX_MESH_BACKOFF,
+};
 
 pub struct FastWalkTime {
     pub signal_fd: i32,
+    /// where the signal listener writes its reports, stderr by default
+    pub report_fd: i32,
     pub global_heap: GlobalHeap,
+    pub mesh_thread: Option<libc::pthread_t>,
+    pub signal_thread: Option<libc::pthread_t>,
 }
 
-pub struct Messloc(pub Mutex<FastWalkTime>);
+/// The global heap behind its lock, plus the pthread key holding each thread's
+/// `ThreadHeap`. Without a key every allocation goes through the global heap.
+/// The owner lookup finds an object's `MiniHeap` without taking the lock.
+pub struct Messloc(
+    pub Mutex<FastWalkTime>,
+    Option<libc::pthread_key_t>,
+    OwnerLookup,
+);
 
 impl Messloc {
     #[must_use]
     pub fn init() -> Self {
-        Self(Mutex::new(FastWalkTime {
-            signal_fd: 0,
-            global_heap: GlobalHeap::init(),
-        }))
+        let mut key = 0;
+        let created = unsafe { libc::pthread_key_create(&mut key, Some(retire_thread_heap)) };
+        let global_heap = GlobalHeap::init();
+        let owners = global_heap.arena.owner_lookup();
+
+        Self(
+            Mutex::new(FastWalkTime {
+                signal_fd: 0,
+                report_fd: libc::STDERR_FILENO,
+                global_heap,
+                mesh_thread: None,
+                signal_thread: None,
+            }),
+            (created == 0).then_some(key),
+            owners,
+        )
+    }
+
+    /// The calling thread's heap, created on first use
+    #[allow(clippy::mut_from_ref)]
+    fn thread_heap(&self) -> Option<&mut ThreadHeap> {
+        let key = self.1?;
+        let mut heap = unsafe { libc::pthread_getspecific(key) }.cast::<ThreadHeap>();
+        if heap.is_null() {
+            heap = ThreadHeap::create();
+            if unsafe { libc::pthread_setspecific(key, heap.cast()) } != 0 {
+                unsafe { ThreadHeap::retire(heap) };
+                return None;
+            }
+        }
+        unsafe { heap.as_mut() }
     }
 
     #[allow(clippy::missing_safety_doc)]
     #[must_use]
     pub unsafe fn allocate(&self, layout: Layout) -> *mut u8 {
-        let heap = &mut self.0.lock().global_heap;
-        heap.malloc(layout.size()) as *mut u8
+        match SizeMap.get_aligned_size_class(layout.size(), layout.align()) {
+            Some(size_class) if let Some(heap) = self.thread_heap() => {
+                let ptr = heap.malloc(size_class);
+                if !ptr.is_null() {
+                    return ptr.cast();
+                }
+
+                self.0
+                    .lock()
+                    .global_heap
+                    .refill_shuffle_vector(heap.shuffle_vector(size_class), size_class);
+                heap.malloc(size_class).cast()
+            }
+            _ => {
+                let heap = &mut self.0.lock().global_heap;
+                heap.memalign(layout.align(), layout.size()) as *mut u8
+            }
+        }
     }
 
     #[allow(clippy::missing_safety_doc)]
     pub unsafe fn deallocate(&self, ptr: *mut u8, layout: Layout) {
-        self.0
-            .lock()
+        let freed_locally = SizeMap
+            .get_aligned_size_class(layout.size(), layout.align())
+            .zip(self.thread_heap())
+            .map_or(false, |(size_class, heap)| {
+                heap.free(size_class, ptr.cast())
+            });
+        if freed_locally {
+            return;
+        }
+
+        // objects of other threads' heaps are released straight into their
+        // bitmap and picked up again when the owner refills
+        self.0.lock().global_heap.free(ptr.cast::<()>());
+    }
+
+    /// Free `ptr` without knowing its layout, as C's `free` does. Pointers that
+    /// weren't allocated by this instance are ignored.
+    ///# Safety
+    /// `ptr` must not be used afterwards
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        if let Some(size) = self.usable_size(ptr) {
+            self.deallocate(ptr, Layout::from_size_align_unchecked(size, 1));
+        }
+    }
+
+    /// Number of bytes usable at `ptr`: the object size of its size class, or
+    /// the page-rounded size of a large allocation. Returns `None` if `ptr`
+    /// wasn't allocated by this instance.
+    ///
+    /// `ptr` has to be the start of an allocation.
+    pub fn usable_size(&self, ptr: *const u8) -> Option<usize> {
+        // this runs on every free, so it reads the page table without the
+        // lock. A live object only changes owner when it is meshed into a
+        // heap of the same size, but the slot of a heap meshed away meanwhile
+        // can be reused, so the size only counts if the owner held still.
+        loop {
+            let mh = unsafe { self.2.get(ptr.cast()) }?;
+            let size = unsafe { mh.as_ref() }.map(|heap| heap.object_size);
+            if unsafe { self.2.get(ptr.cast()) } == Some(mh) {
+                return size;
+            }
+        }
+    }
+
+    /// Allocate zeroed memory, skipping the memset when the object's slot has
+    /// not been used since its span was freshly mapped
+    ///# Safety
+    /// Same contract as [`GlobalAlloc::alloc_zeroed`](core::alloc::GlobalAlloc::alloc_zeroed)
+    #[must_use]
+    pub unsafe fn allocate_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.allocate(layout);
+        if !ptr.is_null() && !self.is_known_zero(ptr.cast(), layout) {
+            ptr.write_bytes(0, layout.size());
+        }
+        ptr
+    }
+
+    fn is_known_zero(&self, ptr: *mut (), layout: Layout) -> bool {
+        let is_zeroed = |mh: *mut MiniHeap| {
+            unsafe { mh.as_ref() }.map_or(false, |heap| heap.is_zeroed(heap.offset_for(ptr)))
+        };
+
+        // heaps attached to this thread can't be meshed away under our feet
+        let local = SizeMap
+            .get_aligned_size_class(layout.size(), layout.align())
+            .zip(self.thread_heap())
+            .and_then(|(size_class, heap)| heap.shuffle_vector(size_class).find(ptr));
+        local.map_or_else(
+            || {
+                let runtime = self.0.lock();
+                unsafe { runtime.global_heap.arena.get_mini_heap(ptr) }.map_or(false, is_zeroed)
+            },
+            is_zeroed,
+        )
+    }
+
+    /// Resize the allocation at `ptr`. Objects stay put as long as the new size
+    /// maps to the same size class, and large allocations are resized in
+    /// place while the pages behind their span are free; everything else is
+    /// moved.
+    ///# Safety
+    /// Same contract as [`GlobalAlloc::realloc`](core::alloc::GlobalAlloc::realloc)
+    #[must_use]
+    pub unsafe fn reallocate(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_class = SizeMap.get_aligned_size_class(layout.size(), layout.align());
+        let new_class = SizeMap.get_aligned_size_class(new_size, layout.align());
+        match (old_class, new_class) {
+            (Some(old), Some(new)) if old == new => return ptr,
+            (None, None) => {
+                let resized = self.0.lock().global_heap.resize_large(ptr.cast(), new_size);
+                if let Some(resized) = resized {
+                    return resized.cast();
+                }
+            }
+            _ => {}
+        }
+
+        let Ok(new_layout) = Layout::from_size_align(new_size, layout.align()) else {
+            return core::ptr::null_mut();
+        };
+        let new_ptr = self.allocate(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.deallocate(ptr, layout);
+        }
+        new_ptr
+    }
+
+    /// Snapshot of the heap's memory use: live objects per size class, large
+    /// allocations, and how many pages meshing has saved
+    pub fn stats(&self) -> HeapStats {
+        self.0.lock().global_heap.stats()
+    }
+
+    /// Set how often the mesh thread wakes up. A zero period stops the thread
+    /// at its next wake up.
+    pub fn set_mesh_period(&self, period: Duration) {
+        self.0.lock().global_heap.mesh_period_ms = period;
+    }
+
+    /// Start a background thread that runs a mesh pass every `mesh_period_ms`,
+    /// backing off while passes don't reclaim anything. Returns `false` if the
+    /// thread is already running or could not be spawned.
+    pub fn start_mesh_thread(&'static self) -> bool {
+        let mut runtime = self.0.lock();
+        if runtime.mesh_thread.is_some() {
+            return false;
+        }
+
+        let mut thread = 0;
+        let spawned = unsafe {
+            pthread_create(
+                &mut thread,
+                None,
+                mesh_thread,
+                (self as *const Self).cast_mut().cast(),
+            )
+        };
+        if spawned.is_ok() {
+            unsafe { libc::pthread_detach(thread) };
+            runtime.mesh_thread = Some(thread);
+        }
+        spawned.is_ok()
+    }
+
+    /// Keep the heap usable across `fork`: it is locked while the process
+    /// forks, and the child moves its spans onto a private copy of the span
+    /// file instead of sharing pages with the parent. Only the forking thread's
+    /// `ThreadHeap` carries over into the child. Returns `false` if the
+    /// handlers can't be installed or too many instances are registered.
+    ///
+    /// Only the forking thread waits for the child's copy. Other threads of
+    /// the parent keep writing to objects they already own while the child
+    /// copies the shared pages, so an object written concurrently with the
+    /// `fork` may show up torn in the child, or reflect writes made after the
+    /// `fork` returned in the parent. Callers that need a consistent snapshot
+    /// have to stop those writers themselves. The pages aren't write
+    /// protected instead, since the child's libc writes to heap memory before
+    /// the fork handlers run.
+    ///
+    /// The reverse holds as well: until its fork handler has moved the spans,
+    /// the child still shares the span file with the parent, and every heap
+    /// write it makes lands in the parent's objects. Child handlers run in the
+    /// order they were registered, so fork handlers installed before this one
+    /// must not write to memory from this heap in the child. Installing these
+    /// handlers first, before any other library registers its own, keeps that
+    /// window down to libc's own bookkeeping.
+    pub fn install_fork_handlers(&'static self) -> bool {
+        fork::register(self)
+    }
+
+    /// Start a thread that forces a mesh pass and dumps heap statistics to
+    /// stderr every time the process receives `SIGRTMIN+8`.
+    ///
+    /// The signal is blocked in the calling thread and consumed via a signalfd,
+    /// so this should be called from the main thread before any other thread
+    /// is spawned: a thread that does not block the signal would be killed by
+    /// it instead. Returns `false` if the listener is already running or could
+    /// not be set up.
+    pub fn start_signal_listener(&'static self) -> bool {
+        let mut runtime = self.0.lock();
+        if runtime.signal_thread.is_some() {
+            return false;
+        }
+
+        let Some(mut mask) = (unsafe { create_signal_mask() }) else {
+            return false;
+        };
+        let fd = unsafe { sig_proc_mask(libc::SIG_BLOCK, &mut mask, core::ptr::null_mut()) }
+            .and_then(|()| unsafe { new_signal_fd(&mut mask) });
+        let Ok(fd) = fd else {
+            return false;
+        };
+        runtime.signal_fd = fd;
+
+        let mut thread = 0;
+        let spawned = unsafe {
+            pthread_create(
+                &mut thread,
+                None,
+                signal_thread,
+                (self as *const Self).cast_mut().cast(),
+            )
+        };
+        if spawned.is_ok() {
+            unsafe { libc::pthread_detach(thread) };
+            runtime.signal_thread = Some(thread);
+        }
+        spawned.is_ok()
+    }
+}
+
+unsafe extern "C" fn retire_thread_heap(heap: *mut c_void) {
+    ThreadHeap::retire(heap.cast());
+}
+
+extern "C" fn signal_thread(messloc: *mut c_void) -> *mut c_void {
+    let messloc = unsafe { messloc.cast::<Messloc>().as_ref().unwrap() };
+    let fd = messloc.0.lock().signal_fd;
+
+    loop {
+        let mut info = unsafe { signalfd_siginfo() };
+        let received = unsafe {
+            read(
+                fd,
+                core::ptr::addr_of_mut!(info).cast(),
+                size_of::<libc::signalfd_siginfo>(),
+            )
+        };
+        match received {
+            Ok(()) => {}
+            // interrupted reads are retried, anything else means the fd is gone
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+
+        // formatted on the stack, the allocator must not allocate from itself here
+        let mut report = ArrayString::<4096>::new();
+        let mut runtime = messloc.0.lock();
+        let meshed = runtime.global_heap.mesh_pass();
+        let _ = writeln!(report, "messloc: mesh pass meshed {meshed} spans");
+        let _ = runtime.global_heap.dump_stats(&mut report);
+        let report_fd = runtime.report_fd;
+        drop(runtime);
+
+        unsafe { libc::write(report_fd, report.as_ptr().cast(), report.len()) };
+    }
+
+    messloc.0.lock().signal_thread = None;
+    core::ptr::null_mut()
+}
+
+extern "C" fn mesh_thread(messloc: *mut c_void) -> *mut c_void {
+    let messloc = unsafe { messloc.cast::<Messloc>().as_ref().unwrap() };
+    let mut backoff = 1;
+
+    loop {
+        let period = messloc.0.lock().global_heap.mesh_period_ms;
+        if period.is_zero() {
+            break;
+        }
+        std::thread::sleep(period * backoff);
+
+        let mut runtime = messloc.0.lock();
+        runtime.global_heap.mesh_pass();
+        backoff = if runtime
             .global_heap
-            .free(ptr as *mut (), layout.size());
+            .last_mesh_effective
+            .load(Ordering::Acquire)
+        {
+            1
+        } else {
+            (backoff * 2).min(MAX_MESH_BACKOFF)
+        };
     }
+
+    messloc.0.lock().mesh_thread = None;
+    core::ptr::null_mut()
 }
 
+/// Instances are independent heaps, so they only compare equal to themselves
 impl PartialEq<Self> for Messloc {
-    fn eq(&self, _rhs: &Self) -> bool {
-        // This is a hack to ensure that partial eq can be implemented on other types
-        // Runtime in a singleton instance and hence can be ignored from the partialeq
-        // checks
-
-        true
+    fn eq(&self, rhs: &Self) -> bool {
+        core::ptr::eq(self, rhs)
     }
 }
 
+/// Every span of the instance is unmapped by its arena. The caller has to make
+/// sure no other thread still uses the instance: their `ThreadHeap`s are
+/// leaked rather than retired, since deleting the key skips its destructor.
 impl Drop for Messloc {
-    fn drop(&mut self) {}
+    fn drop(&mut self) {
+        let Some(key) = self.1 else {
+            return;
+        };
+        unsafe {
+            let heap = libc::pthread_getspecific(key).cast::<ThreadHeap>();
+            if !heap.is_null() {
+                ThreadHeap::retire(heap);
+            }
+            libc::pthread_key_delete(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mesh_thread_reclaims_sparse_spans() {
+        let messloc: &'static Messloc = Box::leak(Box::new(Messloc::init()));
+        messloc.set_mesh_period(Duration::from_millis(1));
+
+        let live_spans = || messloc.0.lock().global_heap.arena.iter_mini_heaps().count();
+        {
+            // detached spans with a single live object each are ideal mesh candidates
+            let arena = &mut messloc.0.lock().global_heap.arena;
+            (0..4).for_each(|k| unsafe {
+                let span = arena.alloc_span(2).unwrap();
+                let mh = arena.generate_mini_heap(span, 1024);
+                mh.as_ref().unwrap().malloc_at(k).unwrap();
+            });
+        }
+        assert_eq!(live_spans(), 4);
+        assert_eq!(messloc.0.lock().global_heap.arena.mini_heap_count(), 4);
+
+        assert!(messloc.start_mesh_thread());
+        assert!(!messloc.start_mesh_thread());
+        let reclaimed = (0..1000).any(|_| {
+            std::thread::sleep(Duration::from_millis(1));
+            live_spans() < 4
+        });
+        messloc.set_mesh_period(Duration::ZERO);
+        assert!(reclaimed);
+        let arena = &messloc.0.lock().global_heap.arena;
+        assert_eq!(arena.mini_heap_count(), arena.iter_mini_heaps().count());
+    }
+
+    #[test]
+    fn threads_allocate_from_their_own_heaps_and_free_remotely() {
+        let messloc: &'static Messloc = Box::leak(Box::new(Messloc::init()));
+        let layout = Layout::from_size_align(48, 8).unwrap();
+
+        let handles = (0..4u8)
+            .map(|id| {
+                std::thread::spawn(move || {
+                    let mut local = [core::ptr::null_mut(); 200];
+                    for ptr in &mut local {
+                        *ptr = unsafe { messloc.allocate(layout) };
+                        unsafe { ptr.write_bytes(id, layout.size()) };
+                    }
+                    assert!(local
+                        .iter()
+                        .all(|ptr| unsafe { *ptr.add(layout.size() - 1) } == id));
+                    // hand half of the objects to another thread to free
+                    for ptr in &local[..100] {
+                        unsafe { messloc.deallocate(*ptr, layout) };
+                    }
+                    local[100..]
+                        .iter()
+                        .map(|ptr| *ptr as usize)
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let remote = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>();
+        std::thread::spawn(move || {
+            for ptr in remote {
+                unsafe { messloc.deallocate(ptr as *mut u8, layout) };
+            }
+        })
+        .join()
+        .unwrap();
+
+        // exited threads detached their heaps and every object has been freed
+        let runtime = messloc.0.lock();
+        assert!(runtime.global_heap.arena.iter_mini_heaps().all(|mh| {
+            let heap = unsafe { mh.as_ref().unwrap() };
+            !heap.is_attached() && heap.is_empty()
+        }));
+    }
+
+    #[test]
+    fn allocations_honor_layout_alignment() {
+        let messloc = Messloc::init();
+        for (size, align) in [(8, 64), (48, 64), (100, 128), (24, 4096), (20_000, 1 << 21)] {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            let ptrs = [(); 4].map(|()| unsafe { messloc.allocate(layout) });
+            for ptr in ptrs {
+                assert!(!ptr.is_null());
+                assert_eq!(ptr as usize % align, 0, "{size} bytes aligned to {align}");
+                unsafe { ptr.write_bytes(1, size) };
+            }
+            for ptr in ptrs {
+                unsafe { messloc.deallocate(ptr, layout) };
+            }
+        }
+    }
+
+    #[test]
+    fn reallocate_keeps_objects_in_place_when_possible() {
+        let messloc = Messloc::init();
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = unsafe { messloc.allocate(layout) };
+        unsafe { ptr.write_bytes(7, 100) };
+
+        // 112 bytes still fit the 112 byte size class
+        assert_eq!(unsafe { messloc.reallocate(ptr, layout, 112) }, ptr);
+        let layout = Layout::from_size_align(112, 8).unwrap();
+        let moved = unsafe { messloc.reallocate(ptr, layout, 30_000) };
+        assert_ne!(moved, ptr);
+        assert!((0..100).all(|k| unsafe { *moved.add(k) } == 7));
+
+        // large allocations grow into the free pages behind their span
+        let layout = Layout::from_size_align(30_000, 8).unwrap();
+        unsafe { moved.add(29_999).write(9) };
+        let grown = unsafe { messloc.reallocate(moved, layout, 1 << 20) };
+        assert_eq!(grown, moved);
+        assert_eq!(unsafe { *grown.add(29_999) }, 9);
+        unsafe { grown.add((1 << 20) - 1).write(3) };
+        let layout = Layout::from_size_align(1 << 20, 8).unwrap();
+        assert_eq!(unsafe { messloc.reallocate(grown, layout, 20_000) }, grown);
+        assert_eq!(unsafe { *grown.add(7) }, 7);
+        unsafe { messloc.deallocate(grown, Layout::from_size_align(20_000, 8).unwrap()) };
+    }
+
+    #[test]
+    fn usable_size_reports_the_rounded_slot() {
+        let messloc = Messloc::init();
+        for size in [100, 5000, 100_000] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let ptr = unsafe { messloc.allocate(layout) };
+            let usable = messloc.usable_size(ptr).unwrap();
+            assert_eq!(Some(usable), crate::size_class_for(size));
+            // the slack is usable without reallocating
+            unsafe { ptr.write_bytes(1, usable) };
+            unsafe { messloc.deallocate(ptr, layout) };
+        }
+        assert_eq!(messloc.usable_size(core::ptr::null()), None);
+    }
+
+    #[test]
+    fn local_frees_do_not_take_the_heap_lock() {
+        let messloc = Messloc::init();
+        let ptr = unsafe { messloc.allocate(Layout::from_size_align(48, 8).unwrap()) };
+        let large = unsafe { messloc.allocate(Layout::from_size_align(100_000, 8).unwrap()) };
+
+        let runtime = messloc.0.lock();
+        assert_eq!(messloc.usable_size(ptr), Some(48));
+        assert_eq!(messloc.usable_size(large), crate::size_class_for(100_000));
+        unsafe { messloc.free(ptr) };
+        drop(runtime);
+
+        // large objects still go through the global heap
+        unsafe { messloc.free(large) };
+        assert_eq!(messloc.stats().large, crate::LargeStats::default());
+    }
+
+    #[test]
+    fn stats_account_for_small_and_large_objects() {
+        let messloc = Messloc::init();
+        assert_eq!(messloc.stats(), HeapStats::default());
+
+        let small = Layout::from_size_align(48, 8).unwrap();
+        let large = Layout::from_size_align(100_000, 8).unwrap();
+        let (object, big) = unsafe { (messloc.allocate(small), messloc.allocate(large)) };
+        let stats = messloc.stats();
+        let class = stats.size_classes[SizeMap.get_size_class(48).unwrap()];
+        assert_eq!((class.object_size, class.spans), (48, 1));
+        assert!(class.live_objects >= 1);
+        assert_eq!(class.allocated_bytes, class.live_objects * 48);
+        assert_eq!(stats.large.objects, 1);
+        assert_eq!(stats.large.allocated_bytes, 25 * crate::PAGE_SIZE);
+        assert_eq!(stats.pages_committed, stats.pages_reserved);
+
+        unsafe { messloc.deallocate(big, large) };
+        assert_eq!(messloc.stats().large, crate::LargeStats::default());
+        unsafe { messloc.deallocate(object, small) };
+    }
+
+    #[test]
+    fn allocate_zeroed_clears_reused_slots() {
+        let messloc = Messloc::init();
+        for size in [64, 100_000] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let fresh = unsafe { messloc.allocate_zeroed(layout) };
+            assert!(messloc.is_known_zero(fresh.cast(), layout));
+            unsafe { fresh.write_bytes(0xff, size) };
+            unsafe { messloc.deallocate(fresh, layout) };
+
+            // enough objects to reuse the dirty slot
+            let ptrs = [(); 80].map(|()| unsafe { messloc.allocate_zeroed(layout) });
+            for ptr in ptrs {
+                assert!((0..size).all(|k| unsafe { *ptr.add(k) } == 0));
+            }
+        }
+    }
+
+    #[test]
+    fn instances_are_independent_and_unmap_their_spans_on_drop() {
+        let is_mapped = |ptr: *mut u8| {
+            let page = (ptr as usize & !(crate::PAGE_SIZE - 1)) as *mut c_void;
+            let mut residency = 0u8;
+            unsafe { libc::mincore(page, crate::PAGE_SIZE, &mut residency) == 0 }
+        };
+
+        let first = Messloc::init();
+        let second = Messloc::init();
+        assert!(first == first);
+        assert!(first != second);
+
+        let layout = Layout::from_size_align(64, 8).unwrap();
+        let small = unsafe { first.allocate(layout) };
+        let large = unsafe { first.allocate(Layout::from_size_align(100_000, 8).unwrap()) };
+        assert!(second.usable_size(small.cast()).is_none());
+        // pointers of another instance are ignored
+        unsafe { second.free(small) };
+        assert_eq!(first.usable_size(small.cast()), Some(64));
+
+        drop(first);
+        assert!(!is_mapped(small));
+        assert!(!is_mapped(large));
+        let ptr = unsafe { second.allocate(layout) };
+        unsafe { ptr.write_bytes(1, 64) };
+        unsafe { second.deallocate(ptr, layout) };
+    }
+
+    #[test]
+    fn sigdump_forces_a_mesh_pass() {
+        let messloc: &'static Messloc = Box::leak(Box::new(Messloc::init()));
+        let live_spans = || messloc.0.lock().global_heap.arena.iter_mini_heaps().count();
+        {
+            let arena = &mut messloc.0.lock().global_heap.arena;
+            (0..2).for_each(|k| unsafe {
+                let span = arena.alloc_span(2).unwrap();
+                let mh = arena.generate_mini_heap(span, 1024);
+                mh.as_ref().unwrap().malloc_at(k).unwrap();
+            });
+        }
+
+        let mut fds = [0; 2];
+        unsafe { crate::utils::pipe(&mut fds) }.unwrap();
+        messloc.0.lock().report_fd = fds[1];
+
+        assert!(messloc.start_signal_listener());
+        assert!(!messloc.start_signal_listener());
+        // other test threads don't block the signal, so target the listener directly
+        let listener = messloc.0.lock().signal_thread.unwrap();
+        assert_eq!(
+            unsafe { libc::pthread_kill(listener, crate::utils::sigdump()) },
+            0
+        );
+
+        let mut report = [0u8; 4096];
+        let len = unsafe { libc::read(fds[0], report.as_mut_ptr().cast(), report.len()) };
+        let report = core::str::from_utf8(&report[..usize::try_from(len).unwrap()]).unwrap();
+        assert!(report.starts_with(
+            "messloc: mesh pass meshed 1 spans\nmessloc: 1 mini heaps, 4 pages in use\n"
+        ));
+        assert!(report.contains(" 1024B: 1 spans, 1 meshed, 2 live objects"));
+        assert_eq!(live_spans(), 1);
+        for fd in fds {
+            assert_eq!(unsafe { libc::close(fd) }, 0);
+        }
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn collections_allocate_through_the_allocator_api() {
+        let messloc = Messloc::init();
+        let mut values = Vec::new_in(&messloc);
+        values.extend(0..10_000u32);
+        assert!(messloc.usable_size(values.as_mut_ptr().cast()).is_some());
+        values.truncate(10);
+        values.shrink_to_fit();
+        assert_eq!(values, (0..10).collect::<Vec<_>>());
+
+        // changing the alignment has to move the object
+        let layout = Layout::from_size_align(24, 8).unwrap();
+        let aligned = Layout::from_size_align(24, 512).unwrap();
+        unsafe {
+            let ptr = core::alloc::Allocator::allocate_zeroed(&messloc, layout).unwrap();
+            let ptr = ptr.cast::<u8>();
+            ptr.as_ptr().write(7);
+            let grown = core::alloc::Allocator::grow(&messloc, ptr, layout, aligned).unwrap();
+            assert_eq!(grown.cast::<u8>().as_ptr() as usize % 512, 0);
+            assert_eq!(*grown.cast::<u8>().as_ptr(), 7);
+            core::alloc::Allocator::deallocate(&messloc, grown.cast(), aligned);
+        }
+    }
 }
@@ -1,45 +1,224 @@
 use crate::MAX_MINI_HEAPS_PER_SHUFFLE_VECTOR;
-use crate::{fake_std::dynarray::DynDeq, mini_heap::MiniHeap, rng::Rng};
-use core::ptr::null_mut;
+use crate::{fake_std::dynarray::DynArray, mini_heap::MiniHeap, rng::Rng};
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+// ids start at one, zero marks a detached `MiniHeap`
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// An object slot that has been reserved from one of the attached `MiniHeap`s
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    mini_heap: usize,
+    offset: usize,
+}
 
 pub struct ShuffleVector<const N: usize> {
-    pub mini_heaps: DynDeq<MiniHeap, MAX_MINI_HEAPS_PER_SHUFFLE_VECTOR>,
+    pub mini_heaps: DynArray<MiniHeap, MAX_MINI_HEAPS_PER_SHUFFLE_VECTOR>,
+    entries: [Entry; N],
+    // entries in `offset..N` are available for allocation
+    offset: usize,
     rng: Rng,
+    /// owner id stored in every attached `MiniHeap`
+    id: usize,
 }
 
 impl<const N: usize> ShuffleVector<N> {
     #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         Self {
-            mini_heaps: DynDeq::create(),
+            mini_heaps: DynArray::create(),
+            entries: [Entry::default(); N],
+            offset: N,
             rng: Rng::init(),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub const fn is_exhausted(&self) -> bool {
+        self.offset == N
+    }
+
+    pub const fn is_full(&self) -> bool {
+        self.offset == 0
+    }
+
+    pub fn malloc(&mut self) -> *mut () {
+        if self.is_exhausted() {
+            return null_mut();
+        }
+
+        let entry = self.entries[self.offset];
+        self.offset += 1;
+        match self.mini_heaps.get(entry.mini_heap) {
+            Some(Some(mh)) => unsafe { mh.as_ref().unwrap().ptr_for(entry.offset) },
+            _ => unreachable!(),
         }
     }
 
-    pub fn malloc(&mut self) -> *mut MiniHeap {
-        match self.mini_heaps.pop() {
-                Some(Some(v)) if let capacity = self.mini_heaps.capacity() && capacity >  4 => {
-                    self.shuffle(0, capacity);
-                    v
-                },
-                Some(Some(v)) => v,
-                _ => null_mut()
+    /// Return an object to the vector, placing it at a random position.
+    /// Returns `false` when `mh` is not attached or the vector has no room left,
+    /// in which case the caller has to release the slot in the bitmap itself.
+    pub fn free(&mut self, mh: *mut MiniHeap, ptr: *mut ()) -> bool {
+        let Some(mini_heap) = self.attached_index(mh) else {
+            return false;
+        };
+        if self.is_full() {
+            return false;
+        }
+
+        let heap = unsafe { mh.as_ref().unwrap() };
+        let offset = heap.offset_for(ptr);
+        heap.mark_dirty(offset);
+        self.offset -= 1;
+        self.entries[self.offset] = Entry { mini_heap, offset };
+        let swap_with = self.rng.in_range(self.offset, N - 1);
+        self.entries.swap(self.offset, swap_with);
+        true
+    }
+
+    fn attached_index(&self, mh: *mut MiniHeap) -> Option<usize> {
+        if unsafe { mh.as_ref() }.map_or(true, |heap| heap.owner.load(Ordering::Acquire) != self.id)
+        {
+            return None;
+        }
+        let mini_heaps = unsafe { self.mini_heaps.as_slice().as_ref().unwrap() };
+        mini_heaps.iter().position(|x| *x == Some(mh))
+    }
 
+    /// Find the attached `MiniHeap` that `ptr` was allocated from
+    pub fn find(&self, ptr: *const ()) -> Option<*mut MiniHeap> {
+        let mini_heaps = unsafe { self.mini_heaps.as_slice().as_ref().unwrap() };
+        mini_heaps
+            .iter()
+            .filter_map(|x| *x)
+            .find(|mh| unsafe { mh.as_ref() }.map_or(false, |heap| heap.contains(ptr)))
+    }
+
+    /// Attach `value` so that its free slots can be used to refill the vector.
+    /// Returns `false` when every attachment slot is taken.
+    pub fn insert(&mut self, value: *mut MiniHeap) -> bool {
+        let mini_heaps = unsafe { self.mini_heaps.as_mut_slice().as_mut().unwrap() };
+        match mini_heaps.iter().position(Option::is_none) {
+            Some(pos) => {
+                mini_heaps[pos] = Some(value);
+                unsafe { value.as_ref().unwrap() }
+                    .owner
+                    .store(self.id, Ordering::Release);
+                true
             }
+            None => false,
+        }
     }
 
-    pub fn insert(&mut self, value: *mut MiniHeap) {
-        if self.mini_heaps.push(value).is_none() {
-            todo!()
-        } else {
-            self.mini_heaps.push(value);
+    /// Detach every `MiniHeap` that has no free slots left. This is only done
+    /// once the vector is exhausted so no entry can refer to a detached heap.
+    pub fn detach_full(&mut self, mut release: impl FnMut(*mut MiniHeap)) {
+        if !self.is_exhausted() {
+            return;
+        }
+
+        let mini_heaps = unsafe { self.mini_heaps.as_mut_slice().as_mut().unwrap() };
+        for slot in mini_heaps.iter_mut() {
+            match slot.and_then(|mh| unsafe { mh.as_ref() }.map(|heap| (mh, heap))) {
+                Some((mh, heap)) if heap.is_full() => {
+                    heap.owner.store(0, Ordering::Release);
+                    *slot = None;
+                    release(mh);
+                }
+                _ => {}
+            }
         }
     }
 
+    /// Give every reserved slot back to its `MiniHeap` and detach all of them,
+    /// leaving the vector empty. The slots are released before the heaps are
+    /// detached so this is safe to do without holding the global heap lock.
+    pub fn release_all(&mut self) {
+        for entry in &self.entries[self.offset..] {
+            if let Some(Some(mh)) = self.mini_heaps.get(entry.mini_heap) {
+                unsafe { mh.as_ref().unwrap() }.free_offset(entry.offset);
+            }
+        }
+        self.offset = N;
+
+        let mini_heaps = unsafe { self.mini_heaps.as_mut_slice().as_mut().unwrap() };
+        for slot in mini_heaps.iter_mut() {
+            if let Some(heap) = slot.take().and_then(|mh| unsafe { mh.as_ref() }) {
+                heap.owner.store(0, Ordering::Release);
+            }
+        }
+    }
+
+    /// Reserve free slots of the attached `MiniHeap`s until the vector is full,
+    /// then shuffle the new entries. Returns how many entries were added.
+    pub fn refill(&mut self) -> usize {
+        let before = self.offset;
+        let mini_heaps = unsafe { self.mini_heaps.as_slice().as_ref().unwrap() };
+
+        for (index, slot) in mini_heaps.iter().enumerate() {
+            let Some(heap) = slot.and_then(|mh| unsafe { mh.as_ref() }) else {
+                continue;
+            };
+
+            for offset in heap.bitmap.free_indices(heap.object_count) {
+                if self.is_full() {
+                    break;
+                }
+                if heap.malloc_at(offset).is_some() {
+                    self.offset -= 1;
+                    self.entries[self.offset] = Entry {
+                        mini_heap: index,
+                        offset,
+                    };
+                }
+            }
+        }
+
+        if self.offset < before {
+            self.shuffle(self.offset, N);
+        }
+        before - self.offset
+    }
+
+    /// Fisher-Yates shuffle of the entries in `start..end`
     pub fn shuffle(&mut self, start: usize, end: usize) {
-        (start..=end).rev().enumerate().for_each(|(k, _)| {
+        (start + 1..end).rev().for_each(|k| {
             let random = self.rng.in_range(start, k);
-            self.mini_heaps.swap_indices(k, random);
+            self.entries.swap(k, random);
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{meshable_arena::MeshableArena, MAX_SHUFFLE_VECTOR_LENGTH};
+
+    #[test]
+    fn hands_out_every_slot_of_attached_mini_heaps() {
+        let mut arena = MeshableArena::init();
+        let span = arena.alloc_span(1).unwrap();
+        let mh = unsafe { arena.generate_mini_heap(span, 256) };
+        let mut sv = ShuffleVector::<MAX_SHUFFLE_VECTOR_LENGTH>::new();
+
+        assert!(sv.insert(mh));
+        assert_eq!(sv.refill(), 16);
+
+        let mut seen = [false; 16];
+        (0..16).for_each(|_| {
+            let ptr = sv.malloc();
+            let offset = unsafe { mh.as_ref().unwrap() }.offset_for(ptr);
+            assert!(!seen[offset]);
+            seen[offset] = true;
+        });
+        assert!(sv.malloc().is_null());
+        assert!(unsafe { mh.as_ref().unwrap() }.is_full());
+
+        let ptr = unsafe { mh.as_ref().unwrap() }.ptr_for(7);
+        assert!(sv.free(mh, ptr));
+        assert_eq!(sv.malloc(), ptr);
+    }
+}
@@ -0,0 +1,173 @@
+use core::ptr::null_mut;
+
+use arrayvec::ArrayVec;
+
+use crate::{
+    class_array::CLASS_TO_SIZE, meshable_arena::MeshableArena, mini_heap::MiniHeap,
+    one_way_mmap_heap::Mapping, rng::Rng, MAX_MESHES, MAX_MESHES_PER_PASS, MAX_MINI_HEAPS,
+    NUM_BINS, SPLIT_MESHER_PROBES,
+};
+
+/// Spans at or above this occupancy (in percent) are never considered for meshing
+pub const DEFAULT_OCCUPANCY_CUTOFF: usize = 80;
+
+/// A pair of heaps where `src` is to be meshed into `dst`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeshPair {
+    pub dst: *mut MiniHeap,
+    pub src: *mut MiniHeap,
+}
+
+pub type MeshPairs = ArrayVec<MeshPair, MAX_MESHES_PER_PASS>;
+
+/// Finds mesh candidates with the `SplitMesher` algorithm from the Mesh paper:
+/// the partially full heaps of a size class are shuffled, split into two halves,
+/// and each heap of the left half is probed against a bounded number of heaps
+/// of the right half.
+pub struct SplitMesher {
+    /// occupancy in percent at which a span is no longer a mesh candidate
+    pub occupancy_cutoff: usize,
+    pub probes: usize,
+    candidates: Mapping,
+}
+
+unsafe impl Send for SplitMesher {}
+
+impl SplitMesher {
+    pub fn init() -> Self {
+        let size = core::mem::size_of::<*mut MiniHeap>() * MAX_MINI_HEAPS;
+        Self {
+            occupancy_cutoff: DEFAULT_OCCUPANCY_CUTOFF,
+            probes: SPLIT_MESHER_PROBES,
+            candidates: Mapping::new(size),
+        }
+    }
+
+    fn is_candidate(&self, heap: &MiniHeap, object_size: usize) -> bool {
+        heap.object_size == object_size
+            && !heap.is_attached()
+            && heap.mesh_count + 1 < MAX_MESHES
+            && heap.in_use_count() * 100 < self.occupancy_cutoff * heap.object_count
+    }
+
+    /// Collect mesh pairs for every size class into `pairs`
+    pub fn find_pairs(&mut self, arena: &MeshableArena, rng: &mut Rng, pairs: &mut MeshPairs) {
+        for object_size in CLASS_TO_SIZE.iter().skip(1).take(NUM_BINS - 1) {
+            if pairs.is_full() {
+                break;
+            }
+            self.find_pairs_in_bin(arena, *object_size as usize, rng, pairs);
+        }
+    }
+
+    fn find_pairs_in_bin(
+        &mut self,
+        arena: &MeshableArena,
+        object_size: usize,
+        rng: &mut Rng,
+        pairs: &mut MeshPairs,
+    ) {
+        let candidates =
+            unsafe { core::slice::from_raw_parts_mut(self.candidates.as_ptr(), MAX_MINI_HEAPS) };
+        let mut count = 0;
+        for mh in arena.iter_mini_heaps() {
+            if self.is_candidate(unsafe { mh.as_ref().unwrap() }, object_size) {
+                candidates[count] = mh;
+                count += 1;
+            }
+        }
+        if count < 2 {
+            return;
+        }
+
+        let candidates = &mut candidates[..count];
+        (1..count).rev().for_each(|k| {
+            candidates.swap(k, rng.in_range(0, k));
+        });
+
+        let (left, right) = candidates.split_at_mut(count / 2);
+        for probe in 0..self.probes.min(right.len()) {
+            for (index, slot) in left.iter_mut().enumerate() {
+                let other = &mut right[(index + probe) % right.len()];
+                if slot.is_null() || other.is_null() {
+                    continue;
+                }
+
+                let (lhs, rhs) = unsafe { (slot.as_ref().unwrap(), other.as_ref().unwrap()) };
+                if lhs.is_meshable_with(rhs) {
+                    // mesh into the fuller heap so fewer objects have to be copied
+                    let pair = if lhs.in_use_count() >= rhs.in_use_count() {
+                        MeshPair {
+                            dst: *slot,
+                            src: *other,
+                        }
+                    } else {
+                        MeshPair {
+                            dst: *other,
+                            src: *slot,
+                        }
+                    };
+                    if pairs.try_push(pair).is_err() {
+                        return;
+                    }
+                    *slot = null_mut();
+                    *other = null_mut();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshable_arena::MeshableArena;
+
+    fn heap_with(arena: &mut MeshableArena, offsets: &[usize]) -> *mut MiniHeap {
+        let span = arena.alloc_span(1).unwrap();
+        let mh = unsafe { arena.generate_mini_heap(span, 256) };
+        for offset in offsets {
+            unsafe { mh.as_ref().unwrap() }.malloc_at(*offset).unwrap();
+        }
+        mh
+    }
+
+    #[test]
+    fn pairs_disjoint_heaps_only() {
+        let mut arena = MeshableArena::init();
+        let a = heap_with(&mut arena, &[0, 1]);
+        let b = heap_with(&mut arena, &[2, 3]);
+        let mut mesher = SplitMesher::init();
+        let mut pairs = MeshPairs::new();
+
+        mesher.find_pairs(&arena, &mut Rng::with_seed(0), &mut pairs);
+        assert_eq!(pairs.len(), 1);
+        let pair = pairs[0];
+        assert!((pair.dst == a && pair.src == b) || (pair.dst == b && pair.src == a));
+
+        let c = heap_with(&mut arena, &[0, 5]);
+        let d = heap_with(&mut arena, &[0, 6]);
+        pairs.clear();
+        mesher.find_pairs(&arena, &mut Rng::with_seed(0), &mut pairs);
+        assert!(pairs.iter().all(|pair| {
+            let (dst, src) = unsafe { (pair.dst.as_ref().unwrap(), pair.src.as_ref().unwrap()) };
+            dst.is_meshable_with(src) && !(pair.dst == c && pair.src == d)
+        }));
+    }
+
+    #[test]
+    fn respects_occupancy_cutoff() {
+        let mut arena = MeshableArena::init();
+        heap_with(&mut arena, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13]);
+        heap_with(&mut arena, &[15]);
+        let mut mesher = SplitMesher::init();
+        let mut pairs = MeshPairs::new();
+
+        mesher.find_pairs(&arena, &mut Rng::with_seed(0), &mut pairs);
+        assert!(pairs.is_empty());
+
+        mesher.occupancy_cutoff = 100;
+        mesher.find_pairs(&arena, &mut Rng::with_seed(0), &mut pairs);
+        assert_eq!(pairs.len(), 1);
+    }
+}
@@ -0,0 +1,45 @@
+//! Plain snapshots of a heap's memory use, for scraping into metrics
+
+use crate::NUM_BINS;
+
+/// Memory use of a heap at the time of [`Messloc::stats`](crate::Messloc::stats)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Small objects by size class, classes without spans are all zero
+    pub size_classes: [SizeClassStats; NUM_BINS],
+    /// Allocations too big for a size class, each with a span of its own
+    pub large: LargeStats,
+    /// Pages of the arena claimed by spans, including the virtual spans that
+    /// have been meshed onto other pages
+    pub pages_reserved: usize,
+    /// Pages actually backed by memory, which meshing keeps below
+    /// `pages_reserved`
+    pub pages_committed: usize,
+    /// Mesh passes run so far
+    pub mesh_passes: usize,
+    /// Spans meshed into others so far
+    pub meshes: usize,
+    /// Pages handed back to the kernel by meshing so far
+    pub pages_reclaimed: usize,
+}
+
+/// Small objects of a single size class. Objects that threads hold in
+/// reserve in their shuffle vectors count as live.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeClassStats {
+    pub object_size: usize,
+    /// Live objects times the object size
+    pub allocated_bytes: usize,
+    pub live_objects: usize,
+    /// Spans with pages of their own
+    pub spans: usize,
+    /// Virtual spans meshed onto the pages of other spans
+    pub meshed_spans: usize,
+}
+
+/// Large allocations, whose sizes are rounded up to whole pages
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LargeStats {
+    pub objects: usize,
+    pub allocated_bytes: usize,
+}
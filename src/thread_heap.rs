@@ -0,0 +1,128 @@
+use core::ptr::null_mut;
+
+use spin::Mutex;
+
+use crate::{
+    one_way_mmap_heap::OneWayMmapHeap, shuffle_vector::ShuffleVector, MAX_SHUFFLE_VECTOR_LENGTH,
+    NUM_BINS,
+};
+
+/// Heaps of exited threads, linked through `ThreadHeap::next` and handed out
+/// again to new threads
+struct Retired(*mut ThreadHeap);
+
+unsafe impl Send for Retired {}
+
+static RETIRED: Mutex<Retired> = Mutex::new(Retired(null_mut()));
+
+/// Per thread front end of the allocator: one shuffle vector per size class
+/// that is only ever touched by its thread, so allocations and frees of objects
+/// from its attached `MiniHeap`s don't need the global heap lock. The global
+/// heap is only involved to refill a vector or to free objects that belong to
+/// other threads.
+pub struct ThreadHeap {
+    shuffle_vectors: [ShuffleVector<MAX_SHUFFLE_VECTOR_LENGTH>; NUM_BINS],
+    next: *mut ThreadHeap,
+}
+
+impl ThreadHeap {
+    /// Reuse the heap of an exited thread or map a fresh one
+    pub fn create() -> *mut Self {
+        {
+            let mut retired = RETIRED.lock();
+            if let Some(heap) = unsafe { retired.0.as_mut() } {
+                retired.0 = heap.next;
+                heap.next = null_mut();
+                return heap;
+            }
+        }
+
+        let heap = unsafe { OneWayMmapHeap.malloc(core::mem::size_of::<Self>()) }.cast::<Self>();
+        unsafe {
+            heap.write(Self {
+                shuffle_vectors: core::array::from_fn(|_| ShuffleVector::new()),
+                next: null_mut(),
+            });
+        }
+        heap
+    }
+
+    pub fn shuffle_vector(
+        &mut self,
+        size_class: usize,
+    ) -> &mut ShuffleVector<MAX_SHUFFLE_VECTOR_LENGTH> {
+        &mut self.shuffle_vectors[size_class]
+    }
+
+    pub fn malloc(&mut self, size_class: usize) -> *mut () {
+        self.shuffle_vectors[size_class].malloc()
+    }
+
+    /// Free `ptr` if it belongs to one of this thread's attached `MiniHeap`s.
+    /// Returns `false` for remote objects, which have to be freed through the
+    /// global heap.
+    pub fn free(&mut self, size_class: usize, ptr: *mut ()) -> bool {
+        let sv = &mut self.shuffle_vectors[size_class];
+        let Some(mh) = sv.find(ptr) else {
+            return false;
+        };
+
+        if !sv.free(mh, ptr) {
+            let heap = unsafe { mh.as_ref().unwrap() };
+            heap.free_offset(heap.offset_for(ptr));
+        }
+        true
+    }
+
+    /// Detach every `MiniHeap` and park the heap for reuse by another thread
+    ///# Safety
+    /// `heap` must come from `create` and must not be used by its thread anymore
+    pub unsafe fn retire(heap: *mut Self) {
+        let thread_heap = heap.as_mut().unwrap();
+        for sv in &mut thread_heap.shuffle_vectors {
+            sv.release_all();
+        }
+
+        let mut retired = RETIRED.lock();
+        thread_heap.next = retired.0;
+        retired.0 = heap;
+    }
+}
+
+/// Keep threads from taking or retiring heaps until `unlock_retired`, so
+/// that a fork doesn't leave the list locked in the child
+pub fn lock_retired() {
+    core::mem::forget(RETIRED.lock());
+}
+
+///# Safety
+/// Must follow a call to `lock_retired`
+pub unsafe fn unlock_retired() {
+    RETIRED.force_unlock();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{global_heap::GlobalHeap, mini_heap::MiniHeap};
+
+    #[test]
+    fn retiring_detaches_heaps_and_returns_reserved_slots() {
+        let mut global = GlobalHeap::init();
+        let heap = unsafe { ThreadHeap::create().as_mut().unwrap() };
+
+        global.refill_shuffle_vector(heap.shuffle_vector(4), 4);
+        let ptr = heap.malloc(4);
+        let mh: *mut MiniHeap = heap.shuffle_vector(4).find(ptr).unwrap();
+        let mini_heap = unsafe { mh.as_ref().unwrap() };
+        assert!(mini_heap.is_attached());
+        assert!(mini_heap.in_use_count() > 1);
+
+        assert!(heap.free(4, ptr));
+        assert!(!heap.free(4, global.malloc(64).cast_mut()));
+
+        unsafe { ThreadHeap::retire(heap) };
+        assert!(!mini_heap.is_attached());
+        assert_eq!(mini_heap.in_use_count(), 0);
+    }
+}
@@ -5,8 +5,8 @@ use core::mem::MaybeUninit;
 use core::ptr::addr_of_mut;
 use libc::{
     c_char, c_void, pthread_attr_t, pthread_t, signalfd_siginfo, sigset_t, size_t,
-    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, F_SETFD, MADV_DONTNEED, PROT_READ, PROT_WRITE,
-    SIGRTMIN,
+    FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, F_SETFD, MADV_DONTNEED, MAP_ANONYMOUS, MAP_FIXED,
+    MAP_NORESERVE, MAP_PRIVATE, MAP_SHARED, PROT_NONE, PROT_READ, PROT_WRITE, SIGRTMIN,
 };
 
 use std::io::Error;
@@ -30,16 +30,19 @@ pub unsafe fn mprotect_write(addr: *mut c_void, size: usize) -> Result<()> {
     OutputWrapper(libc::mprotect(addr, size, PROT_READ | PROT_WRITE)).into()
 }
 
-#[allow(clippy::unnecessary_wraps)]
+/// Map `size` bytes of `fd` at `offset` as shared memory. A non-null `addr`
+/// replaces whatever is currently mapped there.
 pub unsafe fn mmap(addr: *mut c_void, fd: i32, size: usize, offset: usize) -> Result<*mut c_void> {
-    //TODO: replace it with libc's mmap when issue has been figured out
-    Ok(memmap2::MmapRaw::map_raw(fd).unwrap().as_mut_ptr() as *mut c_void)
-    /*
+    let flags = if addr.is_null() {
+        MAP_SHARED | MAP_NORESERVE
+    } else {
+        MAP_FIXED | MAP_SHARED | MAP_NORESERVE
+    };
     let ptr = libc::mmap(
         addr,
         size,
         PROT_READ | PROT_WRITE,
-        MAP_FIXED | MAP_SHARED,
+        flags,
         fd,
         i64::try_from(offset).unwrap(),
     );
@@ -49,7 +52,46 @@ pub unsafe fn mmap(addr: *mut c_void, fd: i32, size: usize, offset: usize) -> Re
     } else {
         Ok(ptr)
     }
-    */
+}
+
+/// Map `size` bytes of private anonymous memory, committed on first touch
+pub unsafe fn mmap_anonymous(size: usize) -> Result<*mut c_void> {
+    let ptr = libc::mmap(
+        core::ptr::null_mut(),
+        size,
+        PROT_READ | PROT_WRITE,
+        MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+        -1,
+        0,
+    );
+
+    if ptr == libc::MAP_FAILED {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ptr)
+    }
+}
+
+/// Reserve `size` bytes of address space without backing it by memory
+pub unsafe fn reserve(size: usize) -> Result<*mut c_void> {
+    let ptr = libc::mmap(
+        core::ptr::null_mut(),
+        size,
+        PROT_NONE,
+        MAP_PRIVATE | MAP_ANONYMOUS | MAP_NORESERVE,
+        -1,
+        0,
+    );
+
+    if ptr == libc::MAP_FAILED {
+        Err(Error::last_os_error())
+    } else {
+        Ok(ptr)
+    }
+}
+
+pub unsafe fn munmap(addr: *mut c_void, size: usize) -> Result<()> {
+    OutputWrapper(libc::munmap(addr, size)).into()
 }
 
 pub unsafe fn mkstemp(file_path: *mut c_char) -> Result<i32> {
@@ -73,7 +115,21 @@ pub unsafe fn make_dir_if_not_exists(file_path: *mut c_char) -> Result<Option<()
 }
 
 pub unsafe fn mkdir(file_path: *mut c_char) -> Result<()> {
-    OutputWrapper(libc::mkdir(file_path, libc::S_IRUSR | libc::S_IWUSR)).into()
+    OutputWrapper(libc::mkdir(file_path, libc::S_IRWXU)).into()
+}
+
+pub unsafe fn rmdir(file_path: *mut c_char) -> Result<()> {
+    OutputWrapper(libc::rmdir(file_path)).into()
+}
+
+pub unsafe fn memfd_create(name: *const c_char, flags: libc::c_uint) -> Result<i32> {
+    let res = libc::memfd_create(name, flags);
+
+    if res >= 0 {
+        Ok(res)
+    } else {
+        Err(Error::last_os_error())
+    }
 }
 
 pub unsafe fn strcat(dest: *mut c_char, src: *const c_char, len: usize) -> *mut c_char {
@@ -94,6 +150,43 @@ pub unsafe fn fallocate(fd: i32, offset: usize, len: usize) -> Result<()> {
     .into()
 }
 
+/// Value of the nul terminated environment variable `name`, read without
+/// allocating. Values that aren't valid UTF-8 are ignored.
+pub fn getenv(name: &[u8]) -> Option<&'static str> {
+    debug_assert_eq!(name.last(), Some(&0));
+    let value = unsafe { libc::getenv(name.as_ptr().cast()) };
+    if value.is_null() {
+        return None;
+    }
+    unsafe { core::ffi::CStr::from_ptr(value) }.to_str().ok()
+}
+
+/// Fill `buf` from the kernel's entropy pool, failing instead of blocking
+/// while the pool isn't initialized yet
+pub fn getrandom(buf: &mut [u8]) -> Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let read =
+            unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), libc::GRND_NONBLOCK) };
+        if let Ok(read) = usize::try_from(read) {
+            filled += read;
+        } else {
+            let err = Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The random bytes the kernel hands every process in its auxiliary vector
+pub fn auxv_random() -> Option<[u8; 16]> {
+    let bytes = unsafe { libc::getauxval(libc::AT_RANDOM) } as *const [u8; 16];
+    unsafe { bytes.as_ref() }.copied()
+}
+
 pub fn get_pid() -> u32 {
     unsafe { libc::getpid() as u32 }
 }
@@ -106,8 +199,10 @@ pub unsafe fn fcntl(fd: i32) -> Result<()> {
     OutputWrapper(libc::fcntl(fd, F_SETFD)).into()
 }
 
-pub unsafe fn pipe(mut fork_pipe: [i32; 2]) -> Result<()> {
-    OutputWrapper(libc::pipe(fork_pipe.as_mut_ptr())).into()
+/// Open a pipe into `fork_pipe`, closed on exec so that spawned programs
+/// don't hold on to either end
+pub unsafe fn pipe(fork_pipe: &mut [i32; 2]) -> Result<()> {
+    OutputWrapper(libc::pipe2(fork_pipe.as_mut_ptr(), libc::O_CLOEXEC)).into()
 }
 
 pub unsafe fn close(fd: i32) -> Result<()> {
@@ -120,23 +215,23 @@ pub unsafe fn read(fd: i32, buf: *mut c_void, len: usize) -> Result<()> {
     if res >= 0 {
         Ok(())
     } else {
-        unreachable!()
+        Err(Error::last_os_error())
     }
 }
 
+/// Block until the other end of the pipe `fd` is written to or closed
 pub unsafe fn wait_till_memory_ready(fd: i32) {
     let mut buf = [0u8; 4];
-    loop {
-        if read(fd, buf.as_mut_ptr() as *mut c_void, 4).is_ok() {
-            break;
-        }
-    }
+    while matches!(
+        read(fd, buf.as_mut_ptr().cast::<c_void>(), 4),
+        Err(err) if err.kind() == std::io::ErrorKind::Interrupted
+    ) {}
 }
 
-pub unsafe fn create_signal_mask() -> Option<*mut sigset_t> {
-    let mask = [0u64; 16].as_mut_ptr() as *mut sigset_t;
-    libc::sigemptyset(mask);
-    let result = libc::sigaddset(mask, SIGRTMIN() + 8);
+pub unsafe fn create_signal_mask() -> Option<sigset_t> {
+    let mut mask = MaybeUninit::<sigset_t>::zeroed().assume_init();
+    libc::sigemptyset(&mut mask);
+    let result = libc::sigaddset(&mut mask, sigdump());
     (result == 0).then_some(mask)
 }
 
@@ -150,27 +245,25 @@ pub unsafe fn signalfd_siginfo() -> signalfd_siginfo {
 }
 
 pub unsafe fn new_signal_fd(mask: *mut sigset_t) -> Result<c_int> {
-    let result = libc::signalfd(-1i32, mask, 0);
+    let result = libc::signalfd(-1i32, mask, libc::SFD_CLOEXEC);
     if result > 0 {
         Ok(result)
     } else {
-        unreachable!()
+        Err(Error::last_os_error())
     }
 }
 
 pub unsafe fn pthread_create(
-    thread: pthread_t,
-    attr: &[pthread_attr_t],
-    start_routine: fn(*mut c_void) -> *mut c_void,
+    thread: &mut pthread_t,
+    attr: Option<&pthread_attr_t>,
+    start_routine: extern "C" fn(*mut c_void) -> *mut c_void,
     args: *mut (),
 ) -> Result<()> {
-    let start_routine =
-        core::mem::transmute::<_, extern "C" fn(*mut c_void) -> *mut c_void>(start_routine);
     OutputWrapper(libc::pthread_create(
-        thread as *mut pthread_t,
-        attr.as_ptr(),
+        thread,
+        attr.map_or(core::ptr::null(), |attr| attr as *const pthread_attr_t),
         start_routine,
-        args as *mut c_void,
+        args.cast(),
     ))
     .into()
 }
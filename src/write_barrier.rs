@@ -0,0 +1,195 @@
+use core::{
+    ffi::{c_int, c_void},
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use libc::{sigaction, siginfo_t, SA_ONSTACK, SA_RESTART, SA_SIGINFO, SIGSEGV, SIG_DFL, SIG_IGN};
+use spin::{Mutex, MutexGuard, Once};
+
+use crate::{
+    meshable_arena::Page,
+    utils::{mprotect_read, mprotect_write},
+    MAX_MESHES,
+};
+
+// only one set of spans is protected at a time, across every instance
+static BARRIER: Mutex<()> = Mutex::new(());
+#[allow(clippy::declare_interior_mutable_const)]
+const UNPROTECTED: AtomicUsize = AtomicUsize::new(0);
+static PROTECTED: [AtomicUsize; MAX_MESHES] = [UNPROTECTED; MAX_MESHES];
+static PROTECTED_SIZE: AtomicUsize = AtomicUsize::new(0);
+// the SIGSEGV action that was installed before ours, `None` if ours isn't
+static PREVIOUS_ACTION: Once<Option<sigaction>> = Once::new();
+
+/// Keeps the spans of a `MiniHeap` read only while it is meshed away. Threads
+/// writing to them fault into a SIGSEGV handler that parks them until the
+/// barrier is dropped, which makes the spans writable again whether or not
+/// they were remapped onto their new pages, and the faulting write is retried
+/// there.
+pub struct WriteBarrier {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl WriteBarrier {
+    /// Make every span of `size` bytes in `spans` read only. Returns `None`,
+    /// leaving the spans writable, if the protection can't be set up.
+    ///# Safety
+    /// `spans` must stay mapped until the barrier is dropped
+    pub unsafe fn protect(
+        mut spans: impl Iterator<Item = *mut Page> + Clone,
+        size: usize,
+    ) -> Option<Self> {
+        if !install_handler() {
+            return None;
+        }
+
+        let guard = BARRIER.lock();
+        PROTECTED_SIZE.store(size, Ordering::Release);
+        for (slot, begin) in PROTECTED.iter().zip(spans.clone()) {
+            slot.store(begin as usize, Ordering::Release);
+        }
+
+        let barrier = Self { _guard: guard };
+        spans
+            .all(|begin| mprotect_read(begin.cast(), size).is_ok())
+            .then_some(barrier)
+    }
+}
+
+/// Spans that were remapped are writable already, the others are made so
+/// before the parked writers are let go, on early returns and unwinding too
+impl Drop for WriteBarrier {
+    fn drop(&mut self) {
+        let size = PROTECTED_SIZE.load(Ordering::Acquire);
+        for slot in &PROTECTED {
+            let begin = slot.load(Ordering::Acquire);
+            if begin != 0 {
+                let _ = unsafe { mprotect_write(begin as *mut c_void, size) };
+            }
+        }
+        for slot in &PROTECTED {
+            slot.store(0, Ordering::Release);
+        }
+    }
+}
+
+fn is_protected(addr: usize) -> bool {
+    let size = PROTECTED_SIZE.load(Ordering::Acquire);
+    PROTECTED.iter().any(|slot| {
+        let begin = slot.load(Ordering::Acquire);
+        begin != 0 && (begin..begin + size).contains(&addr)
+    })
+}
+
+/// Install the SIGSEGV handler once per process, remembering the previous
+/// action for faults that aren't ours
+fn install_handler() -> bool {
+    let previous = PREVIOUS_ACTION.call_once(|| unsafe {
+        let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = on_segv;
+        let mut action = MaybeUninit::<sigaction>::zeroed().assume_init();
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = SA_SIGINFO | SA_ONSTACK | SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous = MaybeUninit::<sigaction>::zeroed().assume_init();
+        (libc::sigaction(SIGSEGV, &action, &mut previous) == 0).then_some(previous)
+    });
+    previous.is_some()
+}
+
+extern "C" fn on_segv(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    let addr = unsafe { (*info).si_addr() } as usize;
+    if is_protected(addr) {
+        while is_protected(addr) {
+            unsafe { libc::sched_yield() };
+        }
+        // returning retries the write, which now hits the remapped span
+        return;
+    }
+
+    let Some(Some(previous)) = PREVIOUS_ACTION.get() else {
+        return;
+    };
+    match previous.sa_sigaction {
+        // restore the previous action and let the fault happen again under it
+        SIG_DFL | SIG_IGN => unsafe {
+            libc::sigaction(SIGSEGV, previous, core::ptr::null_mut());
+        },
+        handler if previous.sa_flags & SA_SIGINFO != 0 => unsafe {
+            let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                core::mem::transmute(handler);
+            handler(signal, info, context);
+        },
+        handler => unsafe {
+            let handler: extern "C" fn(c_int) = core::mem::transmute(handler);
+            handler(signal);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meshable_arena::MeshableArena;
+    use core::sync::atomic::AtomicBool;
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn writers_are_parked_until_the_span_is_remapped() {
+        let mut arena = MeshableArena::init();
+        let span = arena.alloc_span(1).unwrap();
+        let begin = span.begin.cast::<Page>();
+        let size = span.byte_length();
+
+        let barrier = unsafe { WriteBarrier::protect(core::iter::once(begin), size) }.unwrap();
+        let written = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (addr, written) = (begin as usize, written.clone());
+            std::thread::spawn(move || {
+                unsafe { (addr as *mut u64).add(3).write_volatile(42) };
+                written.store(true, Ordering::Release);
+            })
+        };
+
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!written.load(Ordering::Acquire));
+        assert_eq!(unsafe { *span.begin.cast::<u64>().add(3) }, 0);
+
+        unsafe { arena.remap(span.begin, span.offset, span.length) }.unwrap();
+        drop(barrier);
+        writer.join().unwrap();
+        assert!(written.load(Ordering::Acquire));
+        assert_eq!(unsafe { *span.begin.cast::<u64>().add(3) }, 42);
+    }
+
+    #[test]
+    fn dropping_the_barrier_restores_write_access() {
+        let mut arena = MeshableArena::init();
+        let span = arena.alloc_span(1).unwrap();
+        let begin = span.begin.cast::<Page>();
+
+        let barrier = unsafe { WriteBarrier::protect(core::iter::once(begin), span.byte_length()) };
+        drop(barrier.unwrap());
+        unsafe { span.begin.cast::<u64>().write_volatile(7) };
+        assert_eq!(unsafe { *span.begin.cast::<u64>() }, 7);
+    }
+
+    #[test]
+    fn foreign_faults_still_crash() {
+        assert!(install_handler());
+        let page = unsafe { crate::utils::reserve(crate::PAGE_SIZE) }.unwrap();
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            unsafe {
+                page.cast::<u8>().write_volatile(1);
+                libc::_exit(0);
+            }
+        }
+
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(child, &mut status, 0) }, child);
+        assert!(libc::WIFSIGNALED(status));
+        assert_eq!(libc::WTERMSIG(status), SIGSEGV);
+    }
+}